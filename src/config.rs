@@ -1,13 +1,28 @@
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Configuration {
     pub authentication: Authentication,
-    pub model: Model,
+    #[serde(default)]
+    pub administration: Administration,
+    /// The models available to commands, keyed by name
+    pub models: HashMap<String, Model>,
     pub inference: Inference,
     pub commands: HashMap<String, Command>,
+    /// Commands that are only available in specific guilds, keyed by guild ID.
+    /// A guild's command takes precedence over a global command of the same name.
+    #[serde(default)]
+    pub guilds: HashMap<String, Guild>,
+    /// Generations that run on a schedule and are posted to a channel, keyed by name
+    #[serde(default)]
+    pub schedules: HashMap<String, Schedule>,
+    #[serde(default)]
+    pub metrics: Metrics,
 }
 impl Default for Configuration {
     fn default() -> Self {
@@ -15,34 +30,107 @@ impl Default for Configuration {
             authentication: Authentication {
                 discord_token: None,
             },
-            model: Model {
-                path: "models/7B/ggml-alpaca-q4_0.bin".into(),
-                context_token_length: 2048,
-                architecture: llm::ModelArchitecture::Llama.to_string(),
-                prefer_mmap: true,
-                use_gpu: true,
-                gpu_layers: None,
-            },
+            administration: Administration::default(),
+            models: HashMap::from_iter([(
+                "alpaca-7b".into(),
+                Model {
+                    path: "models/7B/ggml-alpaca-q4_0.bin".into(),
+                    context_token_length: 2048,
+                    architecture: llm::ModelArchitecture::Llama.to_string(),
+                    prefer_mmap: true,
+                    use_gpu: true,
+                    gpu_layers: None,
+                    instances: default_instances(),
+                    download_url: None,
+                    download_sha256: None,
+                    lora_paths: vec![],
+                    backend: Backend::default(),
+                    base_url: None,
+                    api_key: None,
+                    remote_model: None,
+                    fallback: None,
+                    is_fallback: false,
+                },
+            )]),
             inference: Inference {
                 thread_count: 8,
-                batch_size: 8,
+                prompt_thread_count: None,
+                generation_thread_count: None,
+                batch_size: default_batch_size(),
                 discord_message_update_interval_ms: 250,
                 replace_newlines: true,
                 show_prompt_template: true,
+                prompt_display_style: PromptDisplayStyle::default(),
+                response_token_reserve: default_response_token_reserve(),
+                trim_incomplete_sentence: false,
+                strike_on_cancel: false,
+                allow_cancel: true,
+                max_tokens: default_max_tokens(),
+                use_embeds: false,
+                conversation_idle_timeout_seconds: default_conversation_idle_timeout_seconds(),
+                max_concurrent_per_user: None,
+                max_queue_size: None,
+                show_stats: false,
+                start_on_model_load_failure: false,
+                message_chunk_size: default_message_chunk_size(),
+                user_cooldown_seconds: 0,
+                persist_sessions: false,
+                session_directory: default_session_directory(),
+                stats_path: default_stats_path(),
+                max_attachment_bytes: default_max_attachment_bytes(),
+                prompt_directory: default_prompt_directory(),
+                max_generation_seconds: None,
+                response_cache_size: 0,
+                cached_response_word_interval_ms: 0,
+                reject_long_prompts: false,
+                min_p: None,
+                respond_to_mentions: false,
+                allow_dms: false,
+                status_reactions: false,
+                default_command: None,
+                global_prompt_prefix: None,
+                global_prompt_suffix: None,
             },
             commands: HashMap::from_iter([
                 (
                     "hallucinate".into(),
                     Command {
                         enabled: false,
+                        aliases: vec![],
                         description: "Hallucinates some text.".into(),
                         prompt: "{{PROMPT}}".into(),
+                        prompt_file: None,
+                        prompt_variants: vec![],
+                        grammar_file: None,
+                        grammar: None,
+                        stop_on_double_newline: false,
+                        stop_sequences: vec![],
+                        thread_count: None,
+                        model: None,
+                        conversational: false,
+                        antiprompt: None,
+                        allowed_roles: vec![],
+                        allowed_users: vec![],
+                        ephemeral: false,
+                        defaults: CommandDefaults::default(),
+                        history_length: 0,
+                        token_biases: HashMap::new(),
+                        system_prompt: None,
+                        replay_conversation: false,
+                        webhook_username: None,
+                        webhook_avatar_url: None,
+                        presets: HashMap::new(),
+                        options: vec![],
+                        max_prompt_chars: None,
+                        context_token_length: None,
+                        discourage_eos: None,
                     },
                 ),
                 (
                     "alpaca".into(),
                     Command {
                         enabled: false,
+                        aliases: vec![],
                         description: "Responds to the provided instruction.".into(),
                         prompt: indoc::indoc! {
                             "Below is an instruction that describes a task. Write a response that appropriately completes the request.
@@ -55,33 +143,672 @@ impl Default for Configuration {
 
                             "
                         }.into(),
+                        prompt_file: None,
+                        prompt_variants: vec![],
+                        grammar_file: None,
+                        grammar: None,
+                        stop_on_double_newline: false,
+                        stop_sequences: vec!["### Instruction:".into()],
+                        thread_count: None,
+                        model: None,
+                        conversational: false,
+                        antiprompt: None,
+                        allowed_roles: vec![],
+                        allowed_users: vec![],
+                        ephemeral: false,
+                        defaults: CommandDefaults::default(),
+                        history_length: 0,
+                        token_biases: HashMap::new(),
+                        system_prompt: None,
+                        replay_conversation: false,
+                        webhook_username: None,
+                        webhook_avatar_url: None,
+                        presets: HashMap::new(),
+                        options: vec![],
+                        max_prompt_chars: None,
+                        context_token_length: None,
+                        discourage_eos: None,
                     },
                 ),
             ]),
+            guilds: HashMap::new(),
+            schedules: HashMap::new(),
+            metrics: Metrics::default(),
         }
     }
 }
 impl Configuration {
     const FILENAME: &str = "config.toml";
+    /// The alternative to [Self::FILENAME] for tooling that generates JSON
+    const JSON_FILENAME: &str = "config.json";
+    /// The environment variable the path to the configuration file can be read from
+    pub const PATH_ENV_VAR: &str = "LLMCORD_CONFIG";
+    /// The environment variables the Discord token can be read from, in order of precedence
+    pub const DISCORD_TOKEN_ENV_VARS: &[&str] = &["LLMCORD_DISCORD_TOKEN", "DISCORD_TOKEN"];
+    /// The placeholders in command prompts that are filled in by llmcord itself,
+    /// rather than by [Command::options]
+    const PLACEHOLDERS: &[&str] = &["{{PROMPT}}", "{{PRESET}}", "{{SYSTEM}}", "{{HISTORY}}"];
 
-    pub fn load() -> anyhow::Result<Self> {
-        let config = if let Ok(file) = std::fs::read_to_string(Self::FILENAME) {
-            toml::from_str(&file).context("failed to load config")?
-        } else {
-            let config = Self::default();
-            config.save()?;
-            config
+    /// Loads the configuration from `path`, or from [Self::FILENAME] or
+    /// [Self::JSON_FILENAME] in the current directory if there isn't one. If the
+    /// file doesn't exist, it's created with the default configuration.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::load_file(path)?,
+            None => {
+                let toml_exists = Path::new(Self::FILENAME).exists();
+                let json_exists = Path::new(Self::JSON_FILENAME).exists();
+                if toml_exists && json_exists {
+                    anyhow::bail!(
+                        "both {} and {} exist; remove one of them so that it's clear which is used",
+                        Self::FILENAME,
+                        Self::JSON_FILENAME
+                    );
+                }
+                Self::load_file(Path::new(if json_exists {
+                    Self::JSON_FILENAME
+                } else {
+                    Self::FILENAME
+                }))?
+            }
         };
 
+        // Environment variables take precedence over the configuration file
+        if let Some(token) = Self::DISCORD_TOKEN_ENV_VARS
+            .iter()
+            .find_map(|k| std::env::var(k).ok().filter(|t| !t.is_empty()))
+        {
+            config.authentication.discord_token = Some(token);
+        }
+
+        config.load_prompt_files()?;
+        config.fix_single_brace_placeholders();
+        config.validate()?;
+
         Ok(config)
     }
 
-    fn save(&self) -> anyhow::Result<()> {
-        Ok(std::fs::write(
-            Self::FILENAME,
-            toml::to_string_pretty(self)?,
-        )?)
+    /// Reads the file at `path` as JSON if it has a `.json` extension, or as TOML
+    /// otherwise, creating it if it doesn't exist.
+    fn load_file(path: &Path) -> anyhow::Result<Self> {
+        let file = match std::fs::read_to_string(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let config = Self::default();
+                config.save(path)?;
+                return Ok(config);
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+
+        if is_json(path) {
+            // serde_json's errors already include the line and column
+            serde_json::from_str(&file)
+                .map_err(|err| anyhow::anyhow!("failed to load {}: {err}", path.display()))
+        } else {
+            toml::from_str(&file).map_err(|err| {
+                let location = err
+                    .span()
+                    .map(|span| {
+                        let (line, column) = line_and_column(&file, span.start);
+                        format!(" at line {line}, column {column}")
+                    })
+                    .unwrap_or_default();
+                anyhow::anyhow!(
+                    "failed to load {}{location}: {}",
+                    path.display(),
+                    err.message()
+                )
+            })
+        }
+    }
+
+    /// Reads the templates of commands with a `prompt_file` from the prompt
+    /// directory into their `prompt`, and likewise for `grammar_file`s. Each
+    /// template is only read once.
+    fn load_prompt_files(&mut self) -> anyhow::Result<()> {
+        let directory = self.inference.prompt_directory.clone();
+        let mut templates: HashMap<String, String> = HashMap::new();
+
+        let guild_commands = self.guilds.iter_mut().flat_map(|(id, guild)| {
+            guild
+                .commands
+                .iter_mut()
+                .map(move |(name, command)| (format!("guilds.{id}.commands.{name}"), command))
+        });
+        let commands = self
+            .commands
+            .iter_mut()
+            .map(|(name, command)| (format!("commands.{name}"), command))
+            .chain(guild_commands);
+        for (path, command) in commands {
+            if let Some(file) = &command.grammar_file {
+                let file_path = directory.join(file);
+                let grammar = std::fs::read_to_string(&file_path).with_context(|| {
+                    format!(
+                        "failed to read {path}.grammar_file from {}",
+                        file_path.display()
+                    )
+                })?;
+                anyhow::ensure!(
+                    !grammar.trim().is_empty(),
+                    "{path}.grammar_file ({}) is empty",
+                    file_path.display()
+                );
+                command.grammar = Some(grammar);
+            }
+
+            let Some(file) = &command.prompt_file else { continue; };
+            anyhow::ensure!(
+                command.prompt.is_empty(),
+                "{path} has both a prompt and a prompt_file; remove one of them"
+            );
+
+            command.prompt = match templates.get(file) {
+                Some(template) => template.clone(),
+                None => {
+                    let file_path = directory.join(file);
+                    let template = std::fs::read_to_string(&file_path).with_context(|| {
+                        format!(
+                            "failed to read {path}.prompt_file from {}",
+                            file_path.display()
+                        )
+                    })?;
+                    templates.insert(file.clone(), template.clone());
+                    template
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Corrects `{PROMPT}` to `{{PROMPT}}` in templates that don't have the latter,
+    /// as the single-brace form is an easy mistake that would drop the user's prompt.
+    fn fix_single_brace_placeholders(&mut self) {
+        let guild_commands = self.guilds.iter_mut().flat_map(|(id, guild)| {
+            guild
+                .commands
+                .iter_mut()
+                .map(move |(name, command)| (format!("guilds.{id}.commands.{name}"), command))
+        });
+        let commands = self
+            .commands
+            .iter_mut()
+            .map(|(name, command)| (format!("commands.{name}"), command))
+            .chain(guild_commands);
+        for (path, command) in commands {
+            let variants = command.prompt_variants.iter_mut().map(|v| {
+                (
+                    format!("{path}.prompt_variants.{}.prompt", v.name),
+                    &mut v.prompt,
+                )
+            });
+            let templates =
+                std::iter::once((format!("{path}.prompt"), &mut command.prompt)).chain(variants);
+            for (path, template) in templates {
+                if template.contains("{PROMPT}") && !template.contains("{{PROMPT}}") {
+                    tracing::warn!(
+                        "{path} has `{{PROMPT}}` instead of `{{{{PROMPT}}}}`; treating it as `{{{{PROMPT}}}}`"
+                    );
+                    *template = template.replace("{PROMPT}", "{{PROMPT}}");
+                }
+            }
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.inference.thread_count > 0,
+            "inference.thread_count must be greater than 0"
+        );
+        anyhow::ensure!(
+            self.inference.prompt_thread_count != Some(0)
+                && self.inference.generation_thread_count != Some(0),
+            "inference.prompt_thread_count and inference.generation_thread_count must be greater than 0"
+        );
+        anyhow::ensure!(
+            self.inference.batch_size > 0,
+            "inference.batch_size must be greater than 0"
+        );
+        anyhow::ensure!(
+            self.inference.max_tokens > 0,
+            "inference.max_tokens must be greater than 0"
+        );
+        anyhow::ensure!(
+            (1..=2000).contains(&self.inference.message_chunk_size),
+            "inference.message_chunk_size must be between 1 and 2000, Discord's message length limit"
+        );
+
+        if let Some(min_p) = self.inference.min_p {
+            anyhow::ensure!(
+                (0.0..=1.0).contains(&min_p),
+                "inference.min_p must be between 0 and 1"
+            );
+        }
+
+        anyhow::ensure!(
+            !self.inference.respond_to_mentions || self.inference.default_command.is_some(),
+            "inference.default_command must be set if inference.respond_to_mentions is on"
+        );
+        anyhow::ensure!(
+            !self.inference.allow_dms || self.inference.default_command.is_some(),
+            "inference.default_command must be set if inference.allow_dms is on"
+        );
+        if let Some(name) = &self.inference.default_command {
+            anyhow::ensure!(
+                matches!(self.commands.get(name), Some(c) if c.enabled),
+                "inference.default_command refers to the command `{name}`, which is not defined or not enabled"
+            );
+            anyhow::ensure!(
+                !self.commands[name].options.iter().any(|o| o.required),
+                "inference.default_command refers to the command `{name}`, which has required options that messages can't fill in"
+            );
+        }
+
+        for (name, schedule) in &self.schedules {
+            if let Err(err) = crate::schedule::Cron::parse(&schedule.cron) {
+                anyhow::bail!("schedules.{name}.cron is invalid: {err}");
+            }
+            let command = &schedule.command;
+            anyhow::ensure!(
+                matches!(self.commands.get(command), Some(c) if c.enabled),
+                "schedules.{name}.command refers to the command `{command}`, which is not defined or not enabled"
+            );
+            anyhow::ensure!(
+                !self.commands[command].options.iter().any(|o| o.required),
+                "schedules.{name}.command refers to the command `{command}`, which has required options that schedules can't fill in"
+            );
+        }
+
+        for (name, model) in &self.models {
+            anyhow::ensure!(
+                model.context_token_length > 0,
+                "models.{name}.context_token_length must be greater than 0"
+            );
+            match model.backend {
+                Backend::Local => anyhow::ensure!(
+                    !model.path.as_os_str().is_empty(),
+                    "models.{name}.path must be set for the local backend"
+                ),
+                Backend::OpenAi => anyhow::ensure!(
+                    model.base_url.is_some(),
+                    "models.{name}.base_url must be set for the openai backend"
+                ),
+            }
+            if let Some(fallback) = &model.fallback {
+                anyhow::ensure!(
+                    model.backend == Backend::Local && fallback.backend == Backend::Local,
+                    "models.{name}.fallback is only supported for the local backend"
+                );
+                anyhow::ensure!(
+                    !fallback.path.as_os_str().is_empty() && fallback.context_token_length > 0,
+                    "models.{name}.fallback must have a path and a context_token_length greater than 0"
+                );
+                anyhow::ensure!(
+                    fallback.fallback.is_none(),
+                    "models.{name}.fallback can't have a fallback of its own"
+                );
+            }
+        }
+
+        for id in self.guilds.keys() {
+            anyhow::ensure!(
+                id.parse::<u64>().is_ok(),
+                "guilds.{id} must be the ID of a guild"
+            );
+        }
+
+        for (guild_id, name, command) in self.all_commands().filter(|(_, _, c)| c.enabled) {
+            let path = Self::command_path(guild_id, name);
+            if command.prompt_variants.is_empty() {
+                anyhow::ensure!(
+                    command.prompt.contains("{{PROMPT}}"),
+                    "{path}.prompt must contain `{{{{PROMPT}}}}`, which is replaced with the user's prompt"
+                );
+            }
+            let mut variant_names = HashSet::new();
+            for variant in &command.prompt_variants {
+                let variant_name = &variant.name;
+                anyhow::ensure!(
+                    !variant_name.is_empty(),
+                    "{path}.prompt_variants must all have a name"
+                );
+                anyhow::ensure!(
+                    variant_names.insert(variant_name),
+                    "{path}.prompt_variants has `{variant_name}` more than once"
+                );
+                anyhow::ensure!(
+                    variant.weight > 0,
+                    "{path}.prompt_variants.{variant_name}.weight must be greater than 0"
+                );
+                anyhow::ensure!(
+                    variant.prompt.contains("{{PROMPT}}"),
+                    "{path}.prompt_variants.{variant_name}.prompt must contain `{{{{PROMPT}}}}`, which is replaced with the user's prompt"
+                );
+            }
+            let defaults = [
+                (
+                    "temperature",
+                    command.defaults.temperature,
+                    TEMPERATURE_RANGE,
+                ),
+                ("top_p", command.defaults.top_p, TOP_P_RANGE),
+                (
+                    "repeat_penalty",
+                    command.defaults.repeat_penalty,
+                    REPEAT_PENALTY_RANGE,
+                ),
+            ];
+            for (name, value, range) in defaults {
+                let Some(value) = value else { continue; };
+                anyhow::ensure!(
+                    range.contains(&(value as f64)),
+                    "{path}.defaults.{name} must be between {} and {}",
+                    range.start(),
+                    range.end()
+                );
+            }
+            anyhow::ensure!(
+                command.thread_count != Some(0),
+                "{path}.thread_count must be greater than 0"
+            );
+            anyhow::ensure!(
+                !matches!(command.discourage_eos, Some(bias) if bias < 0.0),
+                "{path}.discourage_eos must not be negative"
+            );
+            anyhow::ensure!(
+                command.max_prompt_chars != Some(0),
+                "{path}.max_prompt_chars must be greater than 0"
+            );
+            anyhow::ensure!(
+                command.antiprompt.is_none() || command.conversational,
+                "{path}.antiprompt only applies to conversational commands"
+            );
+            anyhow::ensure!(
+                command.antiprompt.as_deref() != Some(""),
+                "{path}.antiprompt must not be empty"
+            );
+
+            // Discord allows 25 options per command, and some are taken by the parameters
+            let max_options = 24 - crate::constant::value::PARAMETERS.len();
+            anyhow::ensure!(
+                command.options.len() <= max_options,
+                "{path}.options can have at most {max_options} options"
+            );
+            let mut option_names = HashSet::new();
+            for option in &command.options {
+                let name = &option.name;
+                anyhow::ensure!(
+                    (1..=32).contains(&name.len())
+                        && name
+                            .chars()
+                            .all(|c| matches!(c, 'a'..='z' | '0'..='9' | '-' | '_')),
+                    "{path}.options has `{name}`, but option names must be 1 to 32 lower case letters, digits, `-` or `_`"
+                );
+                anyhow::ensure!(
+                    !crate::constant::value::PARAMETERS.contains(&name.as_str())
+                        && name != crate::constant::value::PRESET
+                        && !Self::PLACEHOLDERS.contains(&option.placeholder().as_str()),
+                    "{path}.options has `{name}`, which is already the name of a parameter or placeholder"
+                );
+                anyhow::ensure!(
+                    option_names.insert(name),
+                    "{path}.options has `{name}` more than once"
+                );
+            }
+            for placeholder in command.templates().flat_map(placeholders) {
+                anyhow::ensure!(
+                    Self::PLACEHOLDERS.contains(&placeholder)
+                        || command.options.iter().any(|o| o.placeholder() == placeholder),
+                    "{path}.prompt has `{placeholder}`, but there's no option in {path}.options for it"
+                );
+            }
+        }
+
+        // Aliases share a namespace with the commands they're registered alongside.
+        // The built-in commands are handled before any configured command, so their
+        // names are taken in every scope.
+        let builtins = crate::constant::command::ALL;
+        let scopes = std::iter::once((None, &self.commands)).chain(
+            self.guild_commands()
+                .map(|(id, commands)| (Some(id), commands)),
+        );
+        for (guild_id, commands) in scopes {
+            let mut names: HashSet<&str> = commands.keys().map(|k| k.as_str()).collect();
+            names.extend(builtins.iter().copied());
+            for (name, command) in commands {
+                let path = Self::command_path(guild_id, name);
+                anyhow::ensure!(
+                    !builtins.contains(&name.as_str()),
+                    "{path} has the name of the built-in command `/{name}`, so it could never be used"
+                );
+                for alias in &command.aliases {
+                    anyhow::ensure!(
+                        !alias.is_empty(),
+                        "{path}.aliases must not contain empty names"
+                    );
+                    anyhow::ensure!(
+                        names.insert(alias),
+                        "{path}.aliases contains `{alias}`, which is already the name of another command, alias or built-in command"
+                    );
+                }
+            }
+        }
+
+        for (guild_id, name, command) in self.all_commands() {
+            let path = Self::command_path(guild_id, name);
+            let remote = self
+                .model_name_for(command)
+                .and_then(|model| self.models.get(model))
+                .is_some_and(|model| model.backend == Backend::OpenAi);
+            if remote {
+                anyhow::ensure!(
+                    !command.conversational,
+                    "{path} is conversational, but its model uses the openai backend, which can't hold conversations"
+                );
+                if !command.token_biases.is_empty() || command.discourage_eos.is_some() {
+                    tracing::warn!(
+                        "{path}.token_biases and {path}.discourage_eos will be ignored, as its model uses the openai backend"
+                    );
+                }
+            } else if command.enabled && command.grammar_file.is_some() {
+                tracing::warn!(
+                    "{path}.grammar_file is set, but grammars are only supported for the openai backend in this build; the command will fail until its model uses it"
+                );
+            }
+            match &command.model {
+                Some(model) => anyhow::ensure!(
+                    self.models.contains_key(model),
+                    "{path}.model refers to the model `{model}`, which is not defined in `models`"
+                ),
+                None => anyhow::ensure!(
+                    self.models.len() == 1,
+                    "{path}.model must be set, as there are {} models defined",
+                    self.models.len()
+                ),
+            }
+            if let Some(length) = command.context_token_length {
+                let model_length = self
+                    .model_name_for(command)
+                    .and_then(|model| self.models.get(model))
+                    .map_or(0, |model| model.context_token_length);
+                anyhow::ensure!(
+                    length > 0 && length <= model_length,
+                    "{path}.context_token_length must be between 1 and its model's context_token_length ({model_length})"
+                );
+                if remote {
+                    tracing::warn!(
+                        "{path}.context_token_length will be ignored, as its model uses the openai backend"
+                    );
+                }
+            }
+        }
+
+        if !cfg!(any(
+            feature = "cublas",
+            feature = "clblast",
+            feature = "metal"
+        )) {
+            for (name, model) in &self.models {
+                if model.gpu_layers.is_some() {
+                    tracing::warn!(
+                        "models.{name}.gpu_layers is set, but llmcord was built without GPU support (the `cublas`, `clblast` or `metal` features); it will be ignored"
+                    );
+                }
+            }
+        }
+
+        if !cfg!(feature = "metrics") && self.metrics.port.is_some() {
+            tracing::warn!(
+                "metrics.port is set, but llmcord was built without the `metrics` feature; metrics will not be served"
+            );
+        }
+
+        let inference = &self.inference;
+        if inference.prompt_thread_count.is_some() || inference.generation_thread_count.is_some() {
+            tracing::info!(
+                "llm uses the same threads for prompt ingestion and generation, so {} threads will be used for both",
+                inference.combined_thread_count()
+            );
+        }
+
+        if let Ok(available) = std::thread::available_parallelism() {
+            for (guild_id, name, command) in self.all_commands() {
+                match command.thread_count {
+                    Some(thread_count) if thread_count > available.get() => tracing::warn!(
+                        "{}.thread_count ({thread_count}) exceeds the available parallelism ({available}); it will be capped",
+                        Self::command_path(guild_id, name)
+                    ),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether or not any enabled command reads the channel's history, which
+    /// requires the Message Content intent.
+    pub fn needs_message_content(&self) -> bool {
+        self.all_commands()
+            .any(|(_, _, c)| c.enabled && c.history_length > 0 && c.uses_history())
     }
+
+    /// The guilds with commands of their own, by ID, along with those commands.
+    pub fn guild_commands(&self) -> impl Iterator<Item = (u64, &HashMap<String, Command>)> {
+        self.guilds
+            .iter()
+            .filter_map(|(id, guild)| Some((id.parse().ok()?, &guild.commands)))
+    }
+
+    /// Every command, global and guild-specific, along with its name and the ID
+    /// of the guild it belongs to, if any.
+    pub fn all_commands(&self) -> impl Iterator<Item = (Option<u64>, &str, &Command)> {
+        let guild_commands = self.guild_commands().flat_map(|(id, commands)| {
+            commands
+                .iter()
+                .map(move |(name, command)| (Some(id), name.as_str(), command))
+        });
+        self.commands
+            .iter()
+            .map(|(name, command)| (None, name.as_str(), command))
+            .chain(guild_commands)
+    }
+
+    /// Looks up a command as seen from a guild: the guild's own command of that
+    /// name if it has one, or the global command otherwise.
+    pub fn command(&self, guild_id: Option<u64>, name: &str) -> Option<&Command> {
+        self.resolve_command(guild_id, name)
+            .map(|(_, command)| command)
+    }
+
+    /// Like [Self::command], but also accepts the command's aliases, and
+    /// returns the command's own name along with it.
+    pub fn resolve_command(&self, guild_id: Option<u64>, name: &str) -> Option<(&str, &Command)> {
+        fn find<'a>(
+            commands: &'a HashMap<String, Command>,
+            name: &str,
+        ) -> Option<(&'a String, &'a Command)> {
+            commands.get_key_value(name).or_else(|| {
+                commands
+                    .iter()
+                    .find(|(_, command)| command.aliases.iter().any(|a| a == name))
+            })
+        }
+        guild_id
+            .and_then(|id| self.guilds.get(&id.to_string()))
+            .and_then(|guild| find(&guild.commands, name))
+            .or_else(|| find(&self.commands, name))
+            .map(|(name, command)| (name.as_str(), command))
+    }
+
+    /// The path to a command in the configuration, for messages about it.
+    pub fn command_path(guild_id: Option<u64>, name: &str) -> String {
+        match guild_id {
+            Some(id) => format!("guilds.{id}.commands.{name}"),
+            None => format!("commands.{name}"),
+        }
+    }
+
+    /// Returns the name of the model the command uses. This is the command's
+    /// `model` if set, or the only defined model otherwise.
+    pub fn model_name_for<'a>(&'a self, command: &'a Command) -> Option<&'a str> {
+        match &command.model {
+            Some(model) => Some(model.as_str()),
+            None if self.models.len() == 1 => self.models.keys().next().map(|k| k.as_str()),
+            None => None,
+        }
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let contents = if is_json(path) {
+            serde_json::to_string_pretty(self)?
+        } else {
+            toml::to_string_pretty(self)?
+        };
+        std::fs::write(path, contents)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |e| e.eq_ignore_ascii_case("json"))
+}
+
+/// Returns the 1-based line and column of the byte `offset` in `text`.
+fn line_and_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Guild {
+    /// The commands available in this guild only, keyed by name
+    #[serde(default)]
+    pub commands: HashMap<String, Command>,
+}
+
+/// A generation that runs at set times, with its response posted to a channel.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Schedule {
+    /// When to run, as a cron expression in UTC: minute, hour, day of month,
+    /// month and day of week. For example, `0 9 * * *` runs at 09:00 every day.
+    pub cron: String,
+    /// The channel to post the response in
+    pub channel_id: u64,
+    /// The global command whose template and settings are used
+    pub command: String,
+    /// The prompt to give the command
+    #[serde(default)]
+    pub prompt: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -89,45 +816,582 @@ pub struct Authentication {
     pub discord_token: Option<String>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Administration {
+    /// The IDs of the users that are allowed to use administrative commands
+    pub user_ids: Vec<u64>,
+    /// The number of recent generations to remember for auditing
+    pub recent_generations_capacity: usize,
+}
+impl Default for Administration {
+    fn default() -> Self {
+        Self {
+            user_ids: vec![],
+            recent_generations_capacity: 20,
+        }
+    }
+}
+impl Administration {
+    pub fn is_admin(&self, user_id: u64) -> bool {
+        self.user_ids.contains(&user_id)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Metrics {
+    /// The port to serve Prometheus metrics on. If not set, metrics will not
+    /// be served. Requires `llmcord` to be compiled with the `metrics` feature.
+    pub port: Option<u16>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Model {
+    /// The path to the model's weights, for the `local` backend
+    #[serde(default)]
     pub path: PathBuf,
     pub context_token_length: usize,
+    /// The model's architecture, for the `local` backend
+    #[serde(default)]
     pub architecture: String,
+    #[serde(default)]
     pub prefer_mmap: bool,
     /// Whether or not to use GPU support. Note that `llmcord` must be
     /// compiled with GPU support for this to work.
+    #[serde(default)]
     pub use_gpu: bool,
-    /// The number of layers to offload to the GPU (if `use_gpu` is on).
-    /// If not set, all layers will be offloaded.
+    /// The number of layers to offload to the GPU (if `use_gpu` is on and
+    /// `llmcord` was compiled with GPU support). If not set, all layers
+    /// will be offloaded.
     pub gpu_layers: Option<usize>,
+    /// The number of instances of the model to load. Each instance has its
+    /// own generation thread and sessions, so requests from different users
+    /// can be processed in parallel; each request goes to the least busy one.
+    /// Note that each instance needs its own copy of the weights in memory,
+    /// unless `prefer_mmap` is on and the pages can be shared between them.
+    #[serde(default = "default_instances")]
+    pub instances: usize,
+    /// If set, and there is no model at `path`, the model will be
+    /// downloaded from this URL to `path` on startup.
+    pub download_url: Option<String>,
+    /// The expected SHA256 of the downloaded model, in hex. If set, the
+    /// download will be rejected if it doesn't match.
+    pub download_sha256: Option<String>,
+    /// The paths of LoRA adapters to apply on top of the model, in order
+    #[serde(default)]
+    pub lora_paths: Vec<PathBuf>,
+    /// Where the model runs
+    #[serde(default)]
+    pub backend: Backend,
+    /// For the `openai` backend, the URL of the server, which must have an
+    /// OpenAI-compatible `/v1/completions` endpoint, such as `http://localhost:8000`
+    pub base_url: Option<String>,
+    /// For the `openai` backend, the API key to send to the server, if it needs one
+    pub api_key: Option<String>,
+    /// For the `openai` backend, the name of the model to ask the server for. If
+    /// not set, the model's name in `models` is used.
+    pub remote_model: Option<String>,
+    /// A model to load in this one's place if it fails to load, such as a
+    /// smaller model for when this one doesn't fit in memory. It's used under
+    /// this model's name, and it isn't downloaded if it's missing.
+    pub fallback: Option<Box<Model>>,
+    /// Whether or not this is the fallback of the model originally configured
+    /// under its name, which is loaded in its place
+    #[serde(skip)]
+    pub is_fallback: bool,
 }
 impl Model {
     pub fn architecture(&self) -> Option<llm::ModelArchitecture> {
         self.architecture.parse().ok()
     }
 }
+fn default_instances() -> usize {
+    1
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// The model is loaded from `path` and run by llmcord
+    #[default]
+    Local,
+    /// Completions are streamed from an OpenAI-compatible server at `base_url`.
+    /// Conversations and token biases aren't supported.
+    #[serde(rename = "openai")]
+    OpenAi,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Inference {
     /// The number of threads to use
     pub thread_count: usize,
+    /// The number of threads to ingest prompts with, if it should differ from
+    /// `thread_count`. The `llm` version llmcord uses has a single thread count
+    /// for ingestion and generation, so the larger of this and
+    /// `generation_thread_count` is used for both.
+    pub prompt_thread_count: Option<usize>,
+    /// The number of threads to generate responses with, if it should differ
+    /// from `thread_count`. See `prompt_thread_count`.
+    pub generation_thread_count: Option<usize>,
     /// When the prompt is sent to the model, it will be batched. This
     /// controls the size of that batch. Larger values will result in
-    /// faster inference, but will use more memory.
+    /// faster prompt ingestion, but will use more memory. Users can
+    /// override it per-command with the `batch_size` parameter.
+    #[serde(default = "default_batch_size")]
     pub batch_size: usize,
-    /// Low values will result in you getting throttled by Discord
+    /// Low values will result in you getting throttled by Discord. This is the
+    /// shortest interval; long responses are updated less often.
     pub discord_message_update_interval_ms: u64,
     /// Whether or not to replace '\n' with newlines
     pub replace_newlines: bool,
     /// Whether or not to show the entire prompt template, or just
     /// what the user specified
     pub show_prompt_template: bool,
+    /// How to display the prompt above the response
+    #[serde(default)]
+    pub prompt_display_style: PromptDisplayStyle,
+    /// The number of tokens in the context window to keep free for the
+    /// response. Prompts that would eat into this space are truncated
+    /// from the start.
+    #[serde(default = "default_response_token_reserve")]
+    pub response_token_reserve: usize,
+    /// Whether or not to trim the trailing incomplete sentence from a
+    /// response that was cut short before the model finished it
+    #[serde(default)]
+    pub trim_incomplete_sentence: bool,
+    /// Whether or not to strike through cancelled responses and reply with a
+    /// notice, as is done for errors. Otherwise, the partial response is kept
+    /// as it is, marked as cancelled.
+    #[serde(default)]
+    pub strike_on_cancel: bool,
+    /// Whether or not users can cancel their generations, with the button on
+    /// the response, by reacting to it with ⏹️, or with `/cancel`
+    #[serde(default = "default_allow_cancel")]
+    pub allow_cancel: bool,
+    /// Whether or not to show responses in embeds, with the prompt as
+    /// the title, instead of as plain messages
+    #[serde(default)]
+    pub use_embeds: bool,
+    /// The maximum number of tokens to generate. Users can request fewer
+    /// tokens than this, but not more.
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: usize,
+    /// How long a conversation can go unused before it is forgotten
+    #[serde(default = "default_conversation_idle_timeout_seconds")]
+    pub conversation_idle_timeout_seconds: u64,
+    /// The maximum number of generations a user can have queued or in
+    /// progress at once. If not set, there is no limit.
+    pub max_concurrent_per_user: Option<usize>,
+    /// The maximum number of generations that can be queued or in progress
+    /// at once across all users. If not set, there is no limit.
+    pub max_queue_size: Option<usize>,
+    /// Whether or not to show how many tokens were generated, and how
+    /// quickly, after each response
+    #[serde(default)]
+    pub show_stats: bool,
+    /// Whether or not to connect to Discord even if a model fails to
+    /// load. Commands that use the model will reply with the error.
+    #[serde(default)]
+    pub start_on_model_load_failure: bool,
+    /// The number of characters after which a response is split into
+    /// another message. Chunks can run slightly over this so that words
+    /// aren't split, so leave some room under Discord's limit of 2000.
+    #[serde(default = "default_message_chunk_size")]
+    pub message_chunk_size: usize,
+    /// How long a user has to wait after starting a generation before they
+    /// can start another. If 0, there is no cooldown.
+    #[serde(default)]
+    pub user_cooldown_seconds: u64,
+    /// Whether or not to save the ongoing conversations when shutting down,
+    /// and restore them on the next start
+    #[serde(default)]
+    pub persist_sessions: bool,
+    /// The directory to save conversations in, if `persist_sessions` is on.
+    /// Note that each conversation takes as much space as its model's
+    /// context, which can be hundreds of megabytes.
+    #[serde(default = "default_session_directory")]
+    pub session_directory: PathBuf,
+    /// The file the lifetime totals shown by `/stats` are saved to, so that
+    /// they carry over between runs
+    #[serde(default = "default_stats_path")]
+    pub stats_path: PathBuf,
+    /// The largest text file, in bytes, that users can attach as their prompt
+    #[serde(default = "default_max_attachment_bytes")]
+    pub max_attachment_bytes: u64,
+    /// The directory that commands' `prompt_file`s are read from
+    #[serde(default = "default_prompt_directory")]
+    pub prompt_directory: PathBuf,
+    /// How long a generation can run before it is stopped, to keep a model
+    /// that's stuck in a loop from holding up everyone else. If not set,
+    /// generations can run until they finish.
+    pub max_generation_seconds: Option<u64>,
+    /// The number of finished responses to keep, so that a request with the
+    /// same prompt, parameters and seed is answered without running the model
+    /// again. Responses without a fixed seed are never cached. If 0, nothing
+    /// is cached.
+    #[serde(default)]
+    pub response_cache_size: usize,
+    /// How long to wait between each word when showing a cached response, to
+    /// pace it like a generated one. If 0, it's shown all at once.
+    #[serde(default)]
+    pub cached_response_word_interval_ms: u64,
+    /// Whether or not to reject prompts that would leave fewer than
+    /// `response_token_reserve` tokens for the response, instead of
+    /// truncating them
+    #[serde(default)]
+    pub reject_long_prompts: bool,
+    /// If set, min-p sampling is used: tokens less likely than this fraction
+    /// of the most likely token's probability are never picked. Values around
+    /// 0.05 to 0.1 work well.
+    pub min_p: Option<f32>,
+    /// Whether or not to respond to messages that mention the bot, using the
+    /// rest of the message as the prompt for `default_command`
+    #[serde(default)]
+    pub respond_to_mentions: bool,
+    /// Whether or not to respond to direct messages, using the whole message
+    /// as the prompt for `default_command`. Anyone who shares a server with
+    /// the bot can message it, so this is off by default.
+    #[serde(default)]
+    pub allow_dms: bool,
+    /// Whether or not to react to the messages that the bot responds to with an
+    /// hourglass while generating, and a check mark or a cross once done. This
+    /// requires the bot to have the Add Reactions permission.
+    #[serde(default)]
+    pub status_reactions: bool,
+    /// The command whose template and defaults are used for messages that
+    /// mention the bot or are sent to it directly. It must be an enabled
+    /// global command. Formerly `mention_command`.
+    #[serde(alias = "mention_command")]
+    pub default_command: Option<String>,
+    /// Text placed before every command's prompt template. It's given to the
+    /// model, but never shown in Discord. Include any separating newlines.
+    pub global_prompt_prefix: Option<String>,
+    /// Text placed after every command's prompt template. It's given to the
+    /// model, but never shown in Discord.
+    pub global_prompt_suffix: Option<String>,
+}
+impl Inference {
+    /// The single thread count that covers both prompt ingestion and generation,
+    /// as `llm` doesn't support separate ones.
+    fn combined_thread_count(&self) -> usize {
+        self.prompt_thread_count
+            .unwrap_or(self.thread_count)
+            .max(self.generation_thread_count.unwrap_or(self.thread_count))
+    }
+}
+fn default_allow_cancel() -> bool {
+    true
+}
+fn default_batch_size() -> usize {
+    8
+}
+fn default_response_token_reserve() -> usize {
+    128
+}
+fn default_max_tokens() -> usize {
+    512
+}
+fn default_conversation_idle_timeout_seconds() -> u64 {
+    15 * 60
+}
+fn default_message_chunk_size() -> usize {
+    1500
+}
+fn default_session_directory() -> PathBuf {
+    "sessions".into()
+}
+fn default_stats_path() -> PathBuf {
+    "stats.json".into()
+}
+fn default_max_attachment_bytes() -> u64 {
+    64 * 1024
+}
+fn default_prompt_directory() -> PathBuf {
+    "prompts".into()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptDisplayStyle {
+    /// The prompt is shown in bold, with the parts the model has yet to
+    /// read struck through
+    #[default]
+    Strikethrough,
+    /// The prompt is quoted above the response
+    Blockquote,
+    /// Only the response is shown
+    Hidden,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Command {
     pub enabled: bool,
+    /// Other names the command can be used under, such as `c` for `chat`.
+    /// Each is registered with Discord as a command of its own.
+    #[serde(default)]
+    pub aliases: Vec<String>,
     pub description: String,
+    /// The prompt template. `{{PROMPT}}` is replaced with the user's prompt.
+    /// Leave this out to use `prompt_file` or `prompt_variants` instead.
+    #[serde(default)]
     pub prompt: String,
+    /// The name of a file in `inference.prompt_directory` to read the prompt
+    /// template from, such as `assistant.txt`, instead of setting `prompt`
+    pub prompt_file: Option<String>,
+    /// Templates to pick from at random each time the command is used, instead
+    /// of `prompt`. The variant is picked with the generation's seed, so the
+    /// same seed always picks the same variant.
+    #[serde(default)]
+    pub prompt_variants: Vec<PromptVariant>,
+    /// The name of a file in `inference.prompt_directory` with a GBNF grammar,
+    /// such as `json.gbnf`, that responses must follow. This is passed on to
+    /// servers for the `openai` backend; local models can't use it yet.
+    pub grammar_file: Option<String>,
+    /// The contents of `grammar_file`, once loaded
+    #[serde(skip)]
+    pub grammar: Option<String>,
+    /// Whether or not to stop generating when the model emits two
+    /// consecutive newlines. Useful for stopping chat models at the
+    /// end of their turn.
+    #[serde(default)]
+    pub stop_on_double_newline: bool,
+    /// Generation will stop when the model emits any of these. They
+    /// will not be included in the response.
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    /// The number of threads to use for this command. If not set,
+    /// `inference.thread_count` will be used.
+    pub thread_count: Option<usize>,
+    /// The name of the model in `models` to use for this command. This
+    /// can be left unset if there is only one model.
+    pub model: Option<String>,
+    /// Whether or not this command holds a conversation. If it does,
+    /// the model remembers the previous invocations in the channel, and
+    /// each prompt is treated as the next turn in the conversation.
+    #[serde(default)]
+    pub conversational: bool,
+    /// For conversational commands, the text that starts the user's turn,
+    /// such as `User:`. Generation stops as soon as the model emits it, and
+    /// it isn't included in the response. If the next turn's prompt starts
+    /// with it, it isn't repeated, as the model has already written it.
+    pub antiprompt: Option<String>,
+    /// The IDs of the roles that are allowed to use this command. If both
+    /// this and `allowed_users` are empty, everyone can use it.
+    #[serde(default)]
+    pub allowed_roles: Vec<u64>,
+    /// The IDs of the users that are allowed to use this command, regardless
+    /// of their roles.
+    #[serde(default)]
+    pub allowed_users: Vec<u64>,
+    /// Whether or not responses are only shown to the user who asked for
+    /// them. Ephemeral responses are limited to a single message, so long
+    /// responses will be truncated, and they can't be cancelled.
+    #[serde(default)]
+    pub ephemeral: bool,
+    /// The values to use for the command's parameters when the user
+    /// doesn't specify them
+    #[serde(default)]
+    pub defaults: CommandDefaults,
+    /// The number of recent messages in the channel to include in the
+    /// prompt in place of `{{HISTORY}}`, up to 100. Reading messages
+    /// requires the Message Content intent to be enabled for the bot.
+    #[serde(default)]
+    pub history_length: usize,
+    /// Biases to add to the likelihood of specific tokens, keyed by the
+    /// token's text. Negative values make the token less likely; use
+    /// `-inf` to stop it from being generated at all. Each text must be
+    /// a single token in the command's model.
+    #[serde(default)]
+    pub token_biases: HashMap<String, f32>,
+    /// If set, the model's end-of-text token is made less likely by this
+    /// much, so that it writes longer responses. It can still end them.
+    pub discourage_eos: Option<f32>,
+    /// The system prompt to give the model. It replaces `{{SYSTEM}}` in
+    /// `prompt` if present, and is put before the prompt otherwise. It is
+    /// only shown if `inference.show_prompt_template` is on.
+    pub system_prompt: Option<String>,
+    /// Whether or not to show the conversation so far above each response
+    /// of a conversational command
+    #[serde(default)]
+    pub replay_conversation: bool,
+    /// If set, responses are posted through a channel webhook under this
+    /// name instead of as the bot. This requires the bot to have the Manage
+    /// Webhooks permission. Webhook responses are always plain messages,
+    /// and can't be cancelled or regenerated.
+    pub webhook_username: Option<String>,
+    /// The avatar to use for webhook responses, if `webhook_username` is set
+    pub webhook_avatar_url: Option<String>,
+    /// Named snippets of text the user can pick from with the `preset`
+    /// parameter. The chosen preset replaces `{{PRESET}}` in `prompt` if
+    /// present, and is put before the prompt otherwise.
+    #[serde(default)]
+    pub presets: HashMap<String, String>,
+    /// Extra text inputs for the command, such as the language to translate
+    /// to. Each option's value replaces `{{NAME}}` in `prompt`, where `NAME`
+    /// is the option's name in upper case.
+    #[serde(default)]
+    pub options: Vec<CommandOption>,
+    /// The longest prompt, in characters, that users can give this command.
+    /// If not set, prompts are only limited by the model's context.
+    pub max_prompt_chars: Option<usize>,
+    /// If set, the command uses only this much of its model's context, which
+    /// keeps its generations fast. Prompts and conversations are limited to
+    /// it. It can't be more than the model's `context_token_length`.
+    pub context_token_length: Option<usize>,
+}
+impl Command {
+    /// The command's prompt templates: its variants if it has any, or its prompt.
+    pub fn templates(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        if self.prompt_variants.is_empty() {
+            Box::new(std::iter::once(self.prompt.as_str()))
+        } else {
+            Box::new(self.prompt_variants.iter().map(|v| v.prompt.as_str()))
+        }
+    }
+
+    /// The template of the named variant, or the command's prompt if there's no
+    /// such variant.
+    pub fn template(&self, variant: Option<&str>) -> &str {
+        self.prompt_variants
+            .iter()
+            .find(|v| Some(v.name.as_str()) == variant)
+            .map_or(&self.prompt, |v| &v.prompt)
+    }
+
+    /// Whether or not any of the command's templates include the channel's history.
+    pub fn uses_history(&self) -> bool {
+        self.templates().any(|t| t.contains("{{HISTORY}}"))
+    }
+
+    /// Picks one of the prompt variants, in proportion to their weights. The same
+    /// seed always picks the same variant.
+    pub fn pick_variant(&self, seed: u64) -> Option<&PromptVariant> {
+        use rand::{Rng, SeedableRng};
+
+        let total: u64 = self
+            .prompt_variants
+            .iter()
+            .map(|v| u64::from(v.weight))
+            .sum();
+        if total == 0 {
+            return None;
+        }
+        let mut pick = rand::rngs::StdRng::seed_from_u64(seed).gen_range(0..total);
+        self.prompt_variants.iter().find(|v| {
+            let weight = u64::from(v.weight);
+            if pick < weight {
+                return true;
+            }
+            pick -= weight;
+            false
+        })
+    }
+
+    /// The maximum number of tokens to generate, if the user doesn't ask for
+    /// a specific amount.
+    pub fn default_max_tokens(&self, inference: &Inference) -> usize {
+        self.defaults
+            .max_tokens
+            .map_or(inference.max_tokens, |t| t.min(inference.max_tokens))
+    }
+
+    /// Whether or not a user with the given roles is allowed to use this command.
+    pub fn is_allowed(&self, user_id: u64, role_ids: &[u64]) -> bool {
+        (self.allowed_roles.is_empty() && self.allowed_users.is_empty())
+            || self.allowed_users.contains(&user_id)
+            || role_ids.iter().any(|r| self.allowed_roles.contains(r))
+    }
+
+    /// The number of threads to use for this command, capped to the
+    /// parallelism available on this machine.
+    pub fn thread_count(&self, inference: &Inference) -> usize {
+        Self::cap_thread_count(
+            self.thread_count
+                .unwrap_or_else(|| inference.combined_thread_count()),
+        )
+    }
+
+    /// The number of threads to use for commands that don't override it.
+    pub fn default_thread_count(inference: &Inference) -> usize {
+        Self::cap_thread_count(inference.combined_thread_count())
+    }
+
+    fn cap_thread_count(thread_count: usize) -> usize {
+        match std::thread::available_parallelism() {
+            Ok(available) => thread_count.min(available.get()),
+            Err(_) => thread_count,
+        }
+    }
+}
+
+/// One of a command's prompt templates, picked at random.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PromptVariant {
+    /// The variant's name, shown after the response
+    pub name: String,
+    /// How likely the variant is to be picked, relative to the command's other variants
+    #[serde(default = "default_variant_weight")]
+    pub weight: u32,
+    /// The prompt template, like the command's `prompt`
+    pub prompt: String,
+}
+fn default_variant_weight() -> u32 {
+    1
+}
+
+/// An extra text input for a command, filled into its prompt.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandOption {
+    /// The option's name, as shown in Discord. It can only contain lower case
+    /// letters, digits, `-` and `_`.
+    pub name: String,
+    pub description: String,
+    /// Whether or not the user has to fill the option in. If they don't fill
+    /// in an optional one, its placeholder is left empty.
+    #[serde(default)]
+    pub required: bool,
+}
+impl CommandOption {
+    /// The text in the prompt that the option's value replaces.
+    pub fn placeholder(&self) -> String {
+        format!("{{{{{}}}}}", self.name.to_uppercase())
+    }
+}
+
+/// The `{{NAME}}` placeholders in the template, where `NAME` is in upper case.
+fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    template.match_indices("{{").filter_map(|(start, _)| {
+        let end = start + 2 + template[start + 2..].find("}}")? + 2;
+        let name = &template[start + 2..end - 2];
+        (!name.is_empty()
+            && name
+                .chars()
+                .all(|c| matches!(c, 'A'..='Z' | '0'..='9' | '-' | '_')))
+        .then_some(&template[start..end])
+    })
+}
+
+/// The temperatures users can ask for. Beyond 2, responses are mostly noise.
+pub(crate) const TEMPERATURE_RANGE: std::ops::RangeInclusive<f64> = 0.05..=2.0;
+/// The top-p values commands can default to. Lower values only ever sample the
+/// most likely token.
+pub(crate) const TOP_P_RANGE: std::ops::RangeInclusive<f64> = 0.05..=1.0;
+/// The repeat penalties users can ask for. Penalties below 1 encourage repetition.
+pub(crate) const REPEAT_PENALTY_RANGE: std::ops::RangeInclusive<f64> = 1.0..=2.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CommandDefaults {
+    /// The seed to sample with. If not set, a random seed will be used.
+    pub seed: Option<u64>,
+    /// The maximum number of tokens to generate. If not set,
+    /// `inference.max_tokens` will be used.
+    pub max_tokens: Option<usize>,
+    /// The temperature to sample with, from 0.05 to 2. If not set, llm's
+    /// default is used.
+    pub temperature: Option<f32>,
+    /// Only sample from the most likely tokens whose probabilities add up
+    /// to this, from 0.05 to 1. If not set, llm's default is used.
+    pub top_p: Option<f32>,
+    /// How much to penalise recently generated tokens, from 1 (no penalty)
+    /// to 2. If not set, llm's default is used.
+    pub repeat_penalty: Option<f32>,
 }
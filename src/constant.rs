@@ -1,5 +1,63 @@
 /// names of values used in interactions
 pub mod value {
     pub const PROMPT: &str = "prompt";
+    pub const FILE: &str = "file";
     pub const SEED: &str = "seed";
+    pub const STOP_SEQUENCE: &str = "stop_sequence";
+    pub const MAX_TOKENS: &str = "max_tokens";
+    pub const BATCH_SIZE: &str = "batch_size";
+    pub const TEMPERATURE: &str = "temperature";
+    pub const REPEAT_PENALTY: &str = "repeat_penalty";
+    pub const RAW_NEWLINES: &str = "raw_newlines";
+    pub const COMMAND: &str = "command";
+    pub const TEXT: &str = "text";
+    pub const MODEL: &str = "model";
+    pub const SHOW_TOKENS: &str = "show_tokens";
+    pub const PRESET: &str = "preset";
+    pub const DRY_RUN: &str = "dry_run";
+    pub const LANGUAGE: &str = "language";
+    pub const CREATIVITY: &str = "creativity";
+    pub const PAUSE: &str = "pause";
+    pub const RESUME: &str = "resume";
+
+    /// The parameters every configured command is given, other than `preset`
+    pub const PARAMETERS: &[&str] = &[
+        PROMPT,
+        FILE,
+        SEED,
+        STOP_SEQUENCE,
+        MAX_TOKENS,
+        BATCH_SIZE,
+        TEMPERATURE,
+        REPEAT_PENALTY,
+        RAW_NEWLINES,
+        DRY_RUN,
+        LANGUAGE,
+        CREATIVITY,
+    ];
+}
+
+/// names of commands built into the bot, which don't go through the configuration
+pub mod command {
+    pub const RECENT_GENERATIONS: &str = "recent-generations";
+    pub const WHOAMI: &str = "whoami";
+    pub const RESET: &str = "reset";
+    pub const MODELS: &str = "models";
+    pub const TOKENIZE: &str = "tokenize";
+    pub const CANCEL: &str = "cancel";
+    pub const ADMIN: &str = "llmcord-admin";
+    pub const HELP: &str = "help";
+    pub const STATS: &str = "stats";
+
+    pub const ALL: &[&str] = &[
+        RECENT_GENERATIONS,
+        WHOAMI,
+        RESET,
+        MODELS,
+        TOKENIZE,
+        CANCEL,
+        ADMIN,
+        HELP,
+        STATS,
+    ];
 }
@@ -0,0 +1,89 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use crate::config;
+
+/// Downloads the model to its configured path if it's missing and the
+/// configuration opts into downloading it by specifying a `download_url`.
+pub async fn download_model_if_missing(config: &config::Model) -> anyhow::Result<()> {
+    if config.path.exists() {
+        return Ok(());
+    }
+    let Some(url) = config.download_url.as_deref() else { return Ok(()); };
+
+    tracing::info!(
+        path = %config.path.display(),
+        url,
+        "Model not found; downloading it"
+    );
+
+    if let Some(parent) = config.path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    // Download to a temporary file first, so that an interrupted download
+    // isn't mistaken for the model on the next run
+    let partial_path = partial_path(&config.path);
+    let digest = download(url, &partial_path).await?;
+
+    if let Some(expected) = &config.download_sha256 {
+        if !digest.eq_ignore_ascii_case(expected.trim()) {
+            tokio::fs::remove_file(&partial_path).await.ok();
+            anyhow::bail!(
+                "The downloaded model's SHA256 ({digest}) does not match model.download_sha256 ({expected})"
+            );
+        }
+    }
+
+    tokio::fs::rename(&partial_path, &config.path).await?;
+    tracing::info!(path = %config.path.display(), "Downloaded model");
+
+    Ok(())
+}
+
+/// Downloads the file at `url` to `path`, printing progress to stdout, and
+/// returns the hex-encoded SHA256 of its contents.
+async fn download(url: &str, path: &Path) -> anyhow::Result<String> {
+    let mut response = reqwest::get(url)
+        .await
+        .and_then(|r| r.error_for_status())
+        .with_context(|| format!("failed to download model from {url}"))?;
+
+    let total = response.content_length();
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut hasher = Sha256::new();
+    let mut downloaded = 0u64;
+    let mut last_reported_percent = None;
+
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+        hasher.update(&chunk);
+        downloaded += chunk.len() as u64;
+
+        match total {
+            Some(total) if total > 0 => {
+                let percent = downloaded * 100 / total;
+                if last_reported_percent != Some(percent) {
+                    print!("\rDownloading model: {percent}% ({downloaded}/{total} bytes)");
+                    last_reported_percent = Some(percent);
+                }
+            }
+            _ => print!("\rDownloading model: {downloaded} bytes"),
+        }
+        std::io::Write::flush(&mut std::io::stdout()).ok();
+    }
+    println!();
+
+    file.flush().await?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".part");
+    path.with_file_name(file_name)
+}
@@ -1,13 +1,28 @@
-use std::{collections::HashSet, thread::JoinHandle};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
+use flume::r#async::RecvStream;
 use rand::SeedableRng;
-use serenity::model::prelude::MessageId;
+use serde::{Deserialize, Serialize};
+use serenity::model::prelude::{ChannelId, MessageId};
 use thiserror::Error;
 
 #[derive(Debug, Error, Clone)]
 pub enum InferenceError {
     #[error("The generation was cancelled.")]
     Cancelled,
+    #[error("The bot is shutting down.")]
+    ShuttingDown,
+    #[error("The generation took too long, so it was stopped.")]
+    TimedOut,
     #[error("{0}")]
     Custom(String),
 }
@@ -17,92 +32,1465 @@ impl InferenceError {
     }
 }
 
+/// A request for the generation thread to produce a response to a prompt.
 pub struct Request {
+    /// The name of the model to generate with.
+    pub model: String,
+    /// The fully-processed prompt to feed to the model.
     pub prompt: String,
     pub batch_size: usize,
-    pub token_tx: flume::Sender<Token>,
+    /// The number of threads to run inference with.
+    pub thread_count: usize,
+    pub response_token_reserve: usize,
+    /// Generation stops as soon as the model emits any of these. The stop
+    /// sequence itself is not included in the output.
+    pub stop_sequences: Vec<String>,
+    /// For conversations, the text that starts the user's turn. Generation
+    /// stops when the model emits it, and it's left out of the output but kept
+    /// in the session, so it isn't fed in again if the next prompt starts with it.
+    pub antiprompt: Option<String>,
+    /// The maximum number of tokens to generate.
+    pub max_tokens: usize,
+    /// Biases to add to the logits of specific tokens. Negative biases make
+    /// the tokens less likely, and `f32::NEG_INFINITY` stops them entirely.
+    pub token_biases: Vec<(llm::TokenId, f32)>,
+    /// If set, how much less likely to make the model's end-of-text token.
+    pub discourage_eos: Option<f32>,
+    /// If set, tokens less likely than this fraction of the most likely
+    /// token's probability are never sampled.
+    pub min_p: Option<f32>,
+    /// The temperature to sample with. Higher values make the response more
+    /// random. If not set, llm's default is used.
+    pub temperature: Option<f32>,
+    /// Only sample from the most likely tokens whose probabilities add up to
+    /// this. If not set, llm's default is used.
+    pub top_p: Option<f32>,
+    /// How much to penalise tokens that were recently generated. 1.0 is no
+    /// penalty. If not set, llm's default is used.
+    pub repeat_penalty: Option<f32>,
+    /// A GBNF grammar the response must follow. Only models served by another
+    /// server support this; local models fail with [GRAMMAR_UNSUPPORTED].
+    pub grammar: Option<String>,
+    /// If set, how much of the model's context the generation can use, which
+    /// limits the prompt and the response.
+    pub context_size: Option<usize>,
+    /// How long the generation can run before it is stopped. If not set, it
+    /// can run until it finishes.
+    pub timeout: Option<Duration>,
+    /// The message the response is being written to. This is used as the key
+    /// for cancellation.
     pub message_id: MessageId,
+    /// The seed to sample with. If not set, a random seed will be used.
     pub seed: Option<u64>,
+    /// If set, the request continues the conversation in this channel: the
+    /// prompt is fed into the session left over from the previous request
+    /// in the channel, if there is one.
+    pub conversation: Option<ChannelId>,
+    /// Whether or not to play back the conversation so far as [Token::Replayed]
+    /// before the prompt, when continuing a conversation.
+    pub play_back_previous_tokens: bool,
+    /// Whether or not this request continues a previous response rather than
+    /// responding to a new prompt. The prompt isn't sent back as tokens, and
+    /// for conversations, the prompt should be empty, as the response is
+    /// continued from the session.
+    pub continuation: bool,
 }
 
+/// An event produced by the generation thread for a [Request].
 pub enum Token {
     Token(String),
+    /// Text from earlier in the conversation, played back before the prompt
+    /// if the request asked for it.
+    Replayed(String),
+    /// The request is waiting behind this many others for its model instance
+    /// to become free. This is sent when the request is queued and whenever
+    /// it moves up the queue.
+    QueuePosition(usize),
+    /// How many of the prompt's tokens the model has read so far, out of the
+    /// total. This is only sent for prompts too long to be read in one batch,
+    /// after each batch, and the last one is sent once the whole prompt is read.
+    PromptProgress {
+        fed: usize,
+        total: usize,
+    },
+    /// The seed the response is sampled with. This is sent before the response,
+    /// and passing it as [Request::seed] reproduces the response.
+    Seed(u64),
+    /// How many tokens of the model's context the conversation now takes up, out of
+    /// the total. This is only sent for requests in a conversation, once the
+    /// generation has finished.
+    Context {
+        used: usize,
+        size: usize,
+    },
+    /// The generation was stopped before the model finished its response.
+    Truncated,
+    /// The number of tokens generated for the response, and how long that took.
+    /// This is sent once the generation has finished.
+    Stats {
+        count: usize,
+        duration: Duration,
+    },
     Error(InferenceError),
 }
 
-pub fn make_thread(
-    model: Box<dyn llm::Model>,
-    request_rx: flume::Receiver<Request>,
-    cancel_rx: flume::Receiver<MessageId>,
+/// A handle to the generation threads. Each loaded model instance has its own
+/// thread that processes [Request]s one at a time, and requests for a model go to
+/// whichever of its instances has the fewest requests waiting. Requests that are part
+/// of a conversation always go to the same instance, so that they run in order.
+pub struct Generator {
+    pools: HashMap<String, Pool>,
+    /// The models served by other servers, which are generated with in async
+    /// tasks rather than threads
+    remote_models: HashMap<String, RemoteModel>,
+    /// The requests that haven't finished, queued or running, local or remote
+    requests: Requests,
+    /// Set when the generator is shutting down, which stops the threads
+    shutdown: Arc<AtomicBool>,
+    /// Dropped when the generator is shutting down, to wake the idle threads
+    shutdown_tx: Mutex<Option<flume::Sender<()>>>,
+    /// Where conversations are saved on shutdown, if they're persisted
+    session_directory: Option<PathBuf>,
+}
+/// A model served by an OpenAI-compatible server.
+#[derive(Debug, Clone)]
+pub struct RemoteModel {
+    /// The URL of the server, which has a `/v1/completions` endpoint
+    pub base_url: String,
+    pub api_key: Option<String>,
+    /// The name of the model to ask the server for
+    pub model: String,
+}
+
+/// The workers for the instances of a single model.
+struct Pool {
+    workers: Vec<Worker>,
+    next_worker: AtomicUsize,
+    conversations: Conversations,
+    /// The size of the model's vocabulary, used to check that saved conversations
+    /// belong to it
+    vocabulary_size: Option<usize>,
+}
+/// The sessions of ongoing conversations, keyed by channel.
+type Conversations<S = llm::InferenceSession> = Arc<Mutex<HashMap<ChannelId, Conversation<S>>>>;
+struct Conversation<S = llm::InferenceSession> {
+    session: S,
+    last_used: Instant,
+}
+/// The requests that haven't finished, keyed by the message they're writing to.
+type Requests = Arc<Mutex<HashMap<MessageId, Arc<RequestState>>>>;
+#[derive(Default)]
+struct RequestState {
+    cancelled: AtomicBool,
+    /// The token sender of a request waiting in a worker's queue, so that it can be
+    /// told straight away if it's cancelled. The worker takes it when it starts.
+    queued: Mutex<Option<flume::Sender<Token>>>,
+}
+/// A request's entry in [Requests], which is removed when the request ends.
+struct CancelFlag {
+    requests: Requests,
+    message_id: MessageId,
+    state: Arc<RequestState>,
+}
+impl CancelFlag {
+    /// Adds the request, unless the generator is shutting down. This is checked
+    /// under the lock so that a request can't start after shutdown.
+    fn register(
+        requests: &Requests,
+        message_id: MessageId,
+        shutdown: &AtomicBool,
+    ) -> Result<Self, InferenceError> {
+        let mut locked = requests.lock().unwrap();
+        if shutdown.load(Ordering::SeqCst) {
+            return Err(InferenceError::ShuttingDown);
+        }
+        let state = Arc::new(RequestState::default());
+        locked.insert(message_id, state.clone());
+        Ok(Self {
+            requests: requests.clone(),
+            message_id,
+            state,
+        })
+    }
+
+    /// Marks the request as started, returning false if it was cancelled while it
+    /// was queued, in which case it has already been told so.
+    fn start(&self) -> bool {
+        let queued = self.state.queued.lock().unwrap().take();
+        queued.is_some() || !self.state.cancelled.load(Ordering::SeqCst)
+    }
+}
+impl Drop for CancelFlag {
+    fn drop(&mut self) {
+        let mut requests = self.requests.lock().unwrap();
+        // A later request may have taken over the message, such as a regeneration
+        if matches!(requests.get(&self.message_id), Some(s) if Arc::ptr_eq(s, &self.state)) {
+            requests.remove(&self.message_id);
+        }
+    }
+}
+
+fn cancel(requests: &Requests, message_id: MessageId) {
+    let Some(state) = requests.lock().unwrap().get(&message_id).cloned() else { return; };
+    state.cancelled.store(true, Ordering::SeqCst);
+    if let Some(token_tx) = state.queued.lock().unwrap().take() {
+        token_tx.send(Token::Error(InferenceError::Cancelled)).ok();
+    }
+}
+
+/// The token senders of the requests sent to a worker that haven't finished yet,
+/// in order. The front is the request currently being processed.
+type Queue = Arc<Mutex<VecDeque<flume::Sender<Token>>>>;
+struct Worker {
+    thread: JoinHandle<()>,
+    /// The model instance, shared with the thread so that it can be used for
+    /// tokenization outside of generation
+    model: Arc<dyn llm::Model>,
+    request_tx: flume::Sender<(Request, flume::Sender<Token>, CancelFlag)>,
+    queue: Queue,
+}
+impl Generator {
+    /// Starts the generation threads. Conversations that go unused for longer than
+    /// `conversation_idle_timeout` are forgotten to free their memory.
+    ///
+    /// If `session_directory` is set, the conversations saved there by
+    /// [Generator::save_conversations] are restored.
+    pub fn new(
+        models: HashMap<String, Vec<Box<dyn llm::Model>>>,
+        conversation_idle_timeout: Duration,
+        session_directory: Option<PathBuf>,
+    ) -> Self {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        // Nothing is ever sent on this channel; it's disconnected to signal shutdown
+        let (shutdown_tx, shutdown_rx) = flume::unbounded();
+        let pools = models
+            .into_iter()
+            .map(|(name, instances)| {
+                let conversations = Conversations::default();
+                let vocabulary_size = instances.first().map(|m| m.tokenizer().len());
+                if let (Some(directory), Some(model)) = (&session_directory, instances.first()) {
+                    *conversations.lock().unwrap() =
+                        load_conversations(&directory.join(&name), model.as_ref());
+                }
+
+                let workers = instances
+                    .into_iter()
+                    .map(|model| {
+                        let model: Arc<dyn llm::Model> = Arc::from(model);
+                        let (request_tx, request_rx) = flume::unbounded();
+                        let queue = Queue::default();
+
+                        Worker {
+                            thread: make_thread(
+                                model.clone(),
+                                request_rx,
+                                queue.clone(),
+                                conversations.clone(),
+                                conversation_idle_timeout,
+                                shutdown.clone(),
+                                shutdown_rx.clone(),
+                            ),
+                            request_tx,
+                            queue,
+                            model,
+                        }
+                    })
+                    .collect();
+
+                let pool = Pool {
+                    workers,
+                    next_worker: AtomicUsize::new(0),
+                    conversations,
+                    vocabulary_size,
+                };
+                (name, pool)
+            })
+            .collect();
+
+        Self {
+            pools,
+            remote_models: HashMap::new(),
+            requests: Default::default(),
+            shutdown,
+            shutdown_tx: Mutex::new(Some(shutdown_tx)),
+            session_directory,
+        }
+    }
+
+    /// Adds models served by other servers. Requests for them are sent to the
+    /// server as soon as they're made, without queueing.
+    pub fn with_remote_models(mut self, remote_models: HashMap<String, RemoteModel>) -> Self {
+        self.remote_models.extend(remote_models);
+        self
+    }
+
+    /// Queues the request, returning a stream of the tokens it produces.
+    pub fn generate(&self, request: Request) -> Result<RecvStream<'static, Token>, InferenceError> {
+        let not_running = || InferenceError::custom("The generation thread is not running.");
+
+        if let Some(remote) = self.remote_models.get(&request.model) {
+            return self.generate_remotely(remote.clone(), request);
+        }
+
+        let pool = self.pools.get(&request.model).ok_or_else(|| {
+            InferenceError::custom(format!("There is no model named `{}`.", request.model))
+        })?;
+        if pool.workers.is_empty() {
+            return Err(not_running());
+        }
+
+        let worker_count = pool.workers.len();
+        let index = match request.conversation {
+            Some(channel) => channel.0 as usize % worker_count,
+            // Anything else goes to the instance with the fewest requests ahead of it, so
+            // that a long generation doesn't hold up requests while another instance is
+            // free. Ties are broken in round-robin order.
+            None => {
+                let start = pool.next_worker.fetch_add(1, Ordering::Relaxed);
+                (0..worker_count)
+                    .map(|offset| (start + offset) % worker_count)
+                    .min_by_key(|&i| pool.workers[i].queue.lock().unwrap().len())
+                    .unwrap_or_default()
+            }
+        };
+        let worker = &pool.workers[index];
+        let (token_tx, token_rx) = flume::unbounded();
+
+        // Hold the queue's lock while sending so that it stays in the same order as the
+        // channel, and so that the request can't slip in after the thread has shut down
+        let mut queue = worker.queue.lock().unwrap();
+        let cancel_flag = CancelFlag::register(&self.requests, request.message_id, &self.shutdown)?;
+        *cancel_flag.state.queued.lock().unwrap() = Some(token_tx.clone());
+        let position = queue.len();
+        if position > 0 {
+            token_tx.send(Token::QueuePosition(position)).ok();
+        }
+        worker
+            .request_tx
+            .send((request, token_tx.clone(), cancel_flag))
+            .map_err(|_| not_running())?;
+        queue.push_back(token_tx);
+
+        Ok(token_rx.into_stream())
+    }
+
+    /// Starts a task that streams the response from the model's server.
+    fn generate_remotely(
+        &self,
+        remote: RemoteModel,
+        request: Request,
+    ) -> Result<RecvStream<'static, Token>, InferenceError> {
+        let (token_tx, token_rx) = flume::unbounded();
+        let cancel_flag = CancelFlag::register(&self.requests, request.message_id, &self.shutdown)?;
+
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            let result = crate::openai::generate(
+                &remote,
+                &request,
+                &token_tx,
+                &cancel_flag.state.cancelled,
+                &shutdown,
+            )
+            .await;
+            if let Err(err) = result {
+                token_tx.send(Token::Error(err)).ok();
+            }
+        });
+
+        Ok(token_rx.into_stream())
+    }
+
+    /// Cancels the request writing to the given message, if it is queued or running.
+    /// Queued requests end straight away, rather than when their turn comes.
+    pub fn cancel(&self, message_id: MessageId) {
+        cancel(&self.requests, message_id);
+    }
+
+    /// Stops the generation threads. The requests in progress are stopped, and they
+    /// and any queued requests fail with [InferenceError::ShuttingDown].
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.shutdown_tx.lock().unwrap().take();
+    }
+
+    /// Tokenizes the text with the named model, returning each token's bytes and ID.
+    pub fn tokenize(
+        &self,
+        model: &str,
+        text: &str,
+    ) -> Result<Vec<(Vec<u8>, llm::TokenId)>, InferenceError> {
+        if self.remote_models.contains_key(model) {
+            return Err(InferenceError::custom(format!(
+                "The model `{model}` runs on another server, so it can't be tokenized here."
+            )));
+        }
+        let worker = self
+            .pools
+            .get(model)
+            .and_then(|p| p.workers.first())
+            .ok_or_else(|| InferenceError::custom(format!("There is no model named `{model}`.")))?;
+        worker
+            .model
+            .tokenizer()
+            .tokenize(text, false)
+            .map_err(|e| InferenceError::custom(e.to_string()))
+    }
+
+    /// Whether or not all of the generation threads have stopped after a [Generator::shutdown].
+    pub fn is_stopped(&self) -> bool {
+        self.pools
+            .values()
+            .flat_map(|p| &p.workers)
+            .all(|w| w.thread.is_finished())
+            && self.requests.lock().unwrap().is_empty()
+    }
+
+    /// Saves the ongoing conversations to the session directory, if there is one, so that
+    /// they can be restored on the next start. This should only be done once the threads
+    /// have stopped, so that no conversation is in use.
+    pub fn save_conversations(&self) -> anyhow::Result<()> {
+        let Some(directory) = &self.session_directory else { return Ok(()); };
+        for (name, pool) in &self.pools {
+            let mut conversations = pool.conversations.lock().unwrap();
+            let Some(vocabulary_size) = pool.vocabulary_size else { continue; };
+            save_conversations(&directory.join(name), &mut conversations, vocabulary_size)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the conversation in the channel, so that the next request in it starts afresh.
+    pub fn reset_conversation(&self, channel: ChannelId) {
+        for pool in self.pools.values() {
+            pool.conversations.lock().unwrap().remove(&channel);
+        }
+    }
+}
+
+/// What the generation thread needs from a local model. This is implemented for
+/// `llm`'s models, and lets the thread be tested with a scripted model.
+trait LocalModel: Send + Sync + 'static {
+    type Session: Send + 'static;
+
+    fn start_session(&self, request: &Request) -> Self::Session;
+    fn tokenize(
+        &self,
+        text: &str,
+        beginning_of_sentence: bool,
+    ) -> Result<Vec<llm::TokenId>, InferenceError>;
+    fn context_size(&self) -> usize;
+    fn bot_token_id(&self) -> Option<llm::TokenId>;
+    fn eot_token_id(&self) -> llm::TokenId;
+    /// How many tokens the session holds.
+    fn session_length(&self, session: &Self::Session) -> usize;
+    /// The text of the tokens the session holds.
+    fn session_text(&self, session: &Self::Session) -> String;
+    /// Feeds the request's prompt into the session and generates the response,
+    /// calling back with each piece of it. Returns how many tokens were generated,
+    /// and how long that took.
+    fn infer(
+        &self,
+        session: &mut Self::Session,
+        rng: &mut rand::rngs::StdRng,
+        request: &llm::InferenceRequest,
+        callback: impl FnMut(llm::InferenceResponse) -> Result<llm::InferenceFeedback, InferenceError>,
+    ) -> Result<(usize, Duration), InferenceError>;
+}
+impl LocalModel for dyn llm::Model {
+    type Session = llm::InferenceSession;
+
+    fn start_session(&self, request: &Request) -> Self::Session {
+        llm::Model::start_session(
+            self,
+            llm::InferenceSessionConfig {
+                n_batch: request.batch_size,
+                n_threads: request.thread_count,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn tokenize(
+        &self,
+        text: &str,
+        beginning_of_sentence: bool,
+    ) -> Result<Vec<llm::TokenId>, InferenceError> {
+        tokenize(self, text, beginning_of_sentence)
+    }
+
+    fn context_size(&self) -> usize {
+        llm::Model::context_size(self)
+    }
+
+    fn bot_token_id(&self) -> Option<llm::TokenId> {
+        llm::Model::bot_token_id(self)
+    }
+
+    fn eot_token_id(&self) -> llm::TokenId {
+        llm::Model::eot_token_id(self)
+    }
+
+    fn session_length(&self, session: &Self::Session) -> usize {
+        session.tokens().len()
+    }
+
+    fn session_text(&self, session: &Self::Session) -> String {
+        String::from_utf8_lossy(session.decoded_tokens()).into_owned()
+    }
+
+    fn infer(
+        &self,
+        session: &mut Self::Session,
+        rng: &mut rand::rngs::StdRng,
+        request: &llm::InferenceRequest,
+        callback: impl FnMut(llm::InferenceResponse) -> Result<llm::InferenceFeedback, InferenceError>,
+    ) -> Result<(usize, Duration), InferenceError> {
+        session
+            .infer(self, rng, request, &mut Default::default(), callback)
+            .map(|stats| (stats.predict_tokens, stats.predict_duration))
+            .map_err(|e| match e {
+                llm::InferenceError::UserCallback(e) => {
+                    e.downcast::<InferenceError>().unwrap().as_ref().clone()
+                }
+                e => InferenceError::custom(e.to_string()),
+            })
+    }
+}
+
+fn make_thread<M: LocalModel + ?Sized>(
+    model: Arc<M>,
+    request_rx: flume::Receiver<(Request, flume::Sender<Token>, CancelFlag)>,
+    queue: Queue,
+    conversations: Conversations<M::Session>,
+    conversation_idle_timeout: Duration,
+    shutdown: Arc<AtomicBool>,
+    shutdown_rx: flume::Receiver<()>,
 ) -> JoinHandle<()> {
+    // How often an idle thread wakes up to forget idle conversations
+    const IDLE_WAKE_INTERVAL: Duration = Duration::from_secs(30);
+
     std::thread::spawn(move || loop {
-        if let Ok(request) = request_rx.try_recv() {
-            match process_incoming_request(&request, model.as_ref(), &cancel_rx) {
-                Ok(_) => {}
-                Err(e) => {
-                    if let Err(err) = request.token_tx.send(Token::Error(e)) {
-                        eprintln!("Failed to send error: {err:?}");
-                    }
+        {
+            let mut queue = queue.lock().unwrap();
+            if shutdown.load(Ordering::SeqCst) {
+                // Fail anything still waiting, so that its response can say why
+                for (_, token_tx, _) in request_rx.drain() {
+                    token_tx
+                        .send(Token::Error(InferenceError::ShuttingDown))
+                        .ok();
                 }
+                queue.clear();
+                break;
             }
         }
 
-        std::thread::sleep(std::time::Duration::from_millis(5));
+        conversations
+            .lock()
+            .unwrap()
+            .retain(|_, c| c.last_used.elapsed() < conversation_idle_timeout);
+
+        // Sleep until there's a request or the generator shuts down
+        let received = flume::Selector::new()
+            .recv(&request_rx, |r| r.map(Some))
+            .recv(&shutdown_rx, |_| Ok(None))
+            .wait_timeout(IDLE_WAKE_INTERVAL);
+        let (request, token_tx, cancel_flag) = match received {
+            Ok(Ok(Some(received))) => received,
+            // The shutdown is handled at the top of the loop
+            Ok(Ok(None)) | Err(_) => continue,
+            // The generator is gone, so there will be no more requests
+            Ok(Err(_)) => break,
+        };
+
+        // Requests cancelled while they were queued have already been told so
+        if cancel_flag.start() {
+            let _span = tracing::info_span!(
+                "inference",
+                model = %request.model,
+                message = %request.message_id
+            )
+            .entered();
+            if let Err(e) = process_incoming_request(
+                &request,
+                &token_tx,
+                model.as_ref(),
+                &cancel_flag.state.cancelled,
+                &conversations,
+                &shutdown,
+            ) {
+                if let Err(err) = token_tx.send(Token::Error(e)) {
+                    tracing::error!("Failed to send error: {err:?}");
+                }
+            }
+        }
+
+        // The request has ended, so it can't be cancelled any more
+        drop(cancel_flag);
+
+        // Let everyone still waiting know that they've moved up
+        let mut queue = queue.lock().unwrap();
+        queue.pop_front();
+        for (position, token_tx) in queue.iter().enumerate().skip(1) {
+            token_tx.send(Token::QueuePosition(position)).ok();
+        }
     })
 }
 
-fn process_incoming_request(
+/// The error for requests with a grammar for local models, as the version of `llm`
+/// llmcord is built with can't constrain sampling to one.
+pub const GRAMMAR_UNSUPPORTED: &str =
+    "Grammars are not supported by this build of llmcord for local models.";
+
+fn process_incoming_request<M: LocalModel + ?Sized>(
     request: &Request,
-    model: &dyn llm::Model,
-    cancel_rx: &flume::Receiver<MessageId>,
+    token_tx: &flume::Sender<Token>,
+    model: &M,
+    cancelled: &AtomicBool,
+    conversations: &Conversations<M::Session>,
+    shutdown: &AtomicBool,
 ) -> Result<(), InferenceError> {
-    let mut rng = if let Some(seed) = request.seed {
-        rand::rngs::StdRng::seed_from_u64(seed)
-    } else {
-        rand::rngs::StdRng::from_entropy()
+    // The request may have been cancelled while it was queued
+    if cancelled.load(Ordering::SeqCst) {
+        return Err(InferenceError::Cancelled);
+    }
+    if request.grammar.is_some() {
+        return Err(InferenceError::custom(GRAMMAR_UNSUPPORTED));
+    }
+
+    let started = Instant::now();
+    // Pick the seed explicitly, so that it can be reported for reproducing the response
+    let seed = request.seed.unwrap_or_else(rand::random);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    token_tx
+        .send(Token::Seed(seed))
+        .map_err(|_| InferenceError::custom("Failed to send token to channel."))?;
+
+    let reserve = request.response_token_reserve;
+    let context_size = request
+        .context_size
+        .map_or(model.context_size(), |size| size.min(model.context_size()));
+    let conversation = request
+        .conversation
+        .and_then(|channel| conversations.lock().unwrap().remove(&channel));
+
+    // Continue the conversation if there's room left in its session for this turn,
+    // and start a new session otherwise
+    let continued = match conversation {
+        Some(Conversation { session, .. }) => {
+            // If the last turn stopped at the antiprompt, the model has already
+            // started this one
+            let unfed = request
+                .antiprompt
+                .as_deref()
+                .filter(|antiprompt| {
+                    ends_with_antiprompt(&model.session_text(&session), antiprompt)
+                })
+                .and_then(|antiprompt| request.prompt.strip_prefix(antiprompt))
+                .unwrap_or(&request.prompt);
+            let prompt = model.tokenize(unfed, false)?;
+            let used = model.session_length(&session);
+            let already_fed = &request.prompt[..request.prompt.len() - unfed.len()];
+            (used + prompt.len() + reserve <= context_size).then_some((
+                session,
+                prompt,
+                already_fed,
+            ))
+        }
+        None => None,
+    };
+    let (mut session, prompt, already_fed) = match continued {
+        Some(continued) => continued,
+        None if request.continuation && request.conversation.is_some() => {
+            return Err(InferenceError::custom(
+                "The conversation has ended or run out of room, so the response can't be continued.",
+            ));
+        }
+        None => {
+            let session = model.start_session(request);
+            let prompt = model.tokenize(&request.prompt, true)?;
+            (
+                session,
+                truncate_prompt(model, prompt, context_size, reserve),
+                "",
+            )
+        }
     };
 
-    let mut session = model.start_session(Default::default());
+    tracing::debug!(
+        prompt_tokens = prompt.len(),
+        context_tokens = model.session_length(&session),
+        "Starting inference"
+    );
 
     let params = llm::InferenceParameters {
-        sampler: llm::samplers::default_samplers(),
+        sampler: make_sampler(
+            request,
+            request
+                .discourage_eos
+                .map(|bias| (model.eot_token_id(), -bias)),
+        ),
+    };
+
+    let send_token = |t: String| {
+        token_tx
+            .send(Token::Token(t))
+            .map_err(|_| InferenceError::custom("Failed to send token to channel."))
     };
+    // The conversation so far is played back before the prompt. The part of the prompt
+    // that was already in the session is left out of it, as it's sent with the prompt.
+    let mut replayed = String::new();
+    let mut prompt_started = false;
+    let start_prompt = |replayed: &str| -> Result<(), InferenceError> {
+        let replayed = replayed.trim_end();
+        let replayed = replayed
+            .strip_suffix(already_fed.trim_end())
+            .unwrap_or(replayed);
+        if !replayed.is_empty() {
+            token_tx
+                .send(Token::Replayed(replayed.to_string()))
+                .map_err(|_| InferenceError::custom("Failed to send token to channel."))?;
+        }
+        // The part of the prompt that was already in the session is still part of it
+        if !already_fed.is_empty() && !request.continuation {
+            send_token(already_fed.to_string())?;
+        }
+        Ok(())
+    };
+
+    let stops: Vec<_> = request
+        .stop_sequences
+        .iter()
+        .chain(&request.antiprompt)
+        .cloned()
+        .collect();
+    let mut stop_sequences = StopSequenceDetector::new(&stops);
+    // Whether the model ended its response, or we stopped it deliberately
+    let mut finished = false;
+    // The prompt is read a batch at a time, so its progress is reported after each
+    let report_progress = prompt.len() > request.batch_size;
+    let mut fed = 0;
+    // Keep the session within the context the request can use
+    let room = context_size.saturating_sub(model.session_length(&session) + prompt.len());
+
+    let result = model.infer(
+        &mut session,
+        &mut rng,
+        &llm::InferenceRequest {
+            prompt: llm::Prompt::Tokens(&prompt),
+            parameters: &params,
+            play_back_previous_tokens: request.play_back_previous_tokens,
+            maximum_token_count: Some(request.max_tokens.min(room)),
+        },
+        |t| {
+            if cancelled.load(Ordering::SeqCst) {
+                return Err(InferenceError::Cancelled);
+            }
+            if shutdown.load(Ordering::SeqCst) {
+                return Err(InferenceError::ShuttingDown);
+            }
+            if matches!(request.timeout, Some(timeout) if started.elapsed() > timeout) {
+                return Err(InferenceError::TimedOut);
+            }
+
+            if !prompt_started && !matches!(t, llm::InferenceResponse::SnapshotToken(_)) {
+                prompt_started = true;
+                start_prompt(&replayed)?;
+            }
+
+            match t {
+                llm::InferenceResponse::SnapshotToken(t) => replayed.push_str(&t),
+                llm::InferenceResponse::PromptToken(t) => {
+                    // A continued response already has its prompt
+                    if !request.continuation {
+                        send_token(t)?
+                    }
+                    fed += 1;
+                    if report_progress && (fed % request.batch_size == 0 || fed == prompt.len()) {
+                        token_tx
+                            .send(Token::PromptProgress {
+                                fed,
+                                total: prompt.len(),
+                            })
+                            .map_err(|_| {
+                                InferenceError::custom("Failed to send token to channel.")
+                            })?;
+                    }
+                }
+                llm::InferenceResponse::InferredToken(t) => {
+                    let (text, stopped) = stop_sequences.push(&t);
+                    if !text.is_empty() {
+                        send_token(text)?;
+                    }
+                    if stopped {
+                        finished = true;
+                        return Ok(llm::InferenceFeedback::Halt);
+                    }
+                }
+                llm::InferenceResponse::EotToken => finished = true,
+            }
+
+            Ok(llm::InferenceFeedback::Continue)
+        },
+    );
+
+    let context_used = model.session_length(&session);
+    if let Some(channel) = request.conversation {
+        // Keep the session for the next turn, unless something went wrong with it
+        if matches!(
+            result,
+            Ok(_)
+                | Err(InferenceError::Cancelled
+                    | InferenceError::ShuttingDown
+                    | InferenceError::TimedOut)
+        ) {
+            conversations.lock().unwrap().insert(
+                channel,
+                Conversation {
+                    session,
+                    last_used: Instant::now(),
+                },
+            );
+        }
+    }
+    let (count, duration) = result?;
+    // Nothing was read or generated, so the prompt never started
+    if !prompt_started {
+        start_prompt(&replayed)?;
+    }
+    tracing::debug!(
+        tokens = count,
+        duration = ?duration,
+        finished,
+        "Finished inference"
+    );
+    crate::metrics::METRICS.generation(count, started.elapsed());
+
+    // Anything held back that didn't turn out to be a stop sequence is part of the response
+    let remaining = stop_sequences.flush();
+    if !remaining.is_empty() {
+        send_token(remaining)?;
+    }
+
+    if request.conversation.is_some() {
+        token_tx
+            .send(Token::Context {
+                used: context_used,
+                size: context_size,
+            })
+            .map_err(|_| InferenceError::custom("Failed to send token to channel."))?;
+    }
+
+    if !finished {
+        token_tx
+            .send(Token::Truncated)
+            .map_err(|_| InferenceError::custom("Failed to send token to channel."))?;
+    }
+
+    token_tx
+        .send(Token::Stats { count, duration })
+        .map_err(|_| InferenceError::custom("Failed to send token to channel."))?;
 
-    session
-        .infer(
-            model,
-            &mut rng,
-            &llm::InferenceRequest {
-                prompt: (&request.prompt).into(),
-                parameters: &params,
-                play_back_previous_tokens: false,
-                maximum_token_count: None,
+    Ok(())
+}
+
+/// A conversation's session as saved to disk. The vocabulary size is kept to
+/// catch sessions saved with a different model.
+#[derive(Serialize)]
+struct SavedConversationRef<'a> {
+    vocabulary_size: usize,
+    snapshot: llm::InferenceSnapshotRef<'a>,
+}
+#[derive(Deserialize)]
+struct SavedConversation {
+    vocabulary_size: usize,
+    snapshot: llm::InferenceSnapshot,
+}
+
+fn save_conversations(
+    directory: &Path,
+    conversations: &mut HashMap<ChannelId, Conversation>,
+    vocabulary_size: usize,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(directory)?;
+    for (channel, conversation) in conversations.iter_mut() {
+        // SAFETY: the snapshot borrows the session's memory, which nothing else is
+        // using, as the generation threads have stopped
+        let snapshot = unsafe { conversation.session.get_snapshot() };
+        let file = std::fs::File::create(directory.join(format!("{channel}.bin")))?;
+        bincode::serialize_into(
+            std::io::BufWriter::new(file),
+            &SavedConversationRef {
+                vocabulary_size,
+                snapshot,
             },
-            &mut Default::default(),
-            move |t| {
-                let cancellation_requests: HashSet<_> = cancel_rx.drain().collect();
-                if cancellation_requests.contains(&request.message_id) {
-                    return Err(InferenceError::Cancelled);
+        )?;
+    }
+    Ok(())
+}
+
+/// Loads the conversations saved in the directory. Any that can't be loaded,
+/// or that were saved with a different model, are discarded with a warning.
+fn load_conversations(
+    directory: &Path,
+    model: &dyn llm::Model,
+) -> HashMap<ChannelId, Conversation> {
+    let Ok(entries) = std::fs::read_dir(directory) else { return HashMap::new(); };
+
+    let mut conversations = HashMap::new();
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let Some(channel) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse().ok())
+        else {
+            continue;
+        };
+
+        let session = std::fs::File::open(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| {
+                let saved: SavedConversation =
+                    bincode::deserialize_from(std::io::BufReader::new(file))?;
+                anyhow::ensure!(
+                    saved.vocabulary_size == model.tokenizer().len(),
+                    "it was saved with a different model"
+                );
+                Ok(llm::InferenceSession::from_snapshot(saved.snapshot, model)?)
+            });
+        match session {
+            Ok(session) => {
+                conversations.insert(
+                    ChannelId(channel),
+                    Conversation {
+                        session,
+                        last_used: Instant::now(),
+                    },
+                );
+            }
+            Err(err) => tracing::warn!(
+                path = %path.display(),
+                "Discarding the saved conversation: {err}"
+            ),
+        }
+        // The conversation lives in memory from here on
+        std::fs::remove_file(&path).ok();
+    }
+    conversations
+}
+
+/// Detects stop sequences in generated text. Text that could be the start of a stop
+/// sequence is held back until it's clear whether it is one, so that sequences split
+/// across multiple tokens are still caught and kept out of the output.
+pub(crate) struct StopSequenceDetector<'a> {
+    stop_sequences: Vec<&'a str>,
+    pending: String,
+}
+impl<'a> StopSequenceDetector<'a> {
+    pub(crate) fn new(stop_sequences: &'a [String]) -> Self {
+        Self {
+            stop_sequences: stop_sequences
+                .iter()
+                .map(|s| s.as_str())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            pending: String::new(),
+        }
+    }
+
+    /// Adds generated text, returning the text that is now safe to emit and
+    /// whether a stop sequence was encountered.
+    pub(crate) fn push(&mut self, text: &str) -> (String, bool) {
+        self.pending.push_str(text);
+
+        let stop_index = self
+            .stop_sequences
+            .iter()
+            .filter_map(|s| self.pending.find(s))
+            .min();
+        if let Some(index) = stop_index {
+            self.pending.truncate(index);
+            return (std::mem::take(&mut self.pending), true);
+        }
+
+        // Hold back the longest suffix that could be the start of a stop sequence
+        let hold_from = self
+            .pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                let suffix = &self.pending[i..];
+                self.stop_sequences.iter().any(|s| s.starts_with(suffix))
+            })
+            .unwrap_or(self.pending.len());
+
+        let emitted = self.pending[..hold_from].to_string();
+        self.pending.drain(..hold_from);
+        (emitted, false)
+    }
+
+    /// Returns any text that was being held back.
+    pub(crate) fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Builds the sampler for a request: the default samplers, with the token biases
+/// (including any bias against the end-of-text token) and min-p filtering applied first.
+fn make_sampler(
+    request: &Request,
+    eos_bias: Option<(llm::TokenId, f32)>,
+) -> Arc<Mutex<dyn llm::samplers::llm_samplers::types::Sampler<llm::TokenId, f32>>> {
+    use llm::samplers::llm_samplers::{samplers::*, types::SamplerChain};
+
+    // The parameters of llm's default samplers, for when only some are overridden
+    const DEFAULT_REPEAT_PENALTY: f32 = 1.30;
+    const DEFAULT_TEMPERATURE: f32 = 0.80;
+    const DEFAULT_TOP_P: f32 = 0.95;
+
+    let biases: Vec<_> = request
+        .token_biases
+        .iter()
+        .copied()
+        .chain(eos_bias)
+        .collect();
+    let overrides_defaults = request.temperature.is_some()
+        || request.top_p.is_some()
+        || request.repeat_penalty.is_some();
+    if biases.is_empty() && request.min_p.is_none() && !overrides_defaults {
+        return llm::samplers::default_samplers();
+    }
+
+    let mut chain = SamplerChain::new();
+    if !biases.is_empty() {
+        chain += SampleFlatBias::new(biases);
+    }
+    if let Some(min_p) = request.min_p {
+        chain += crate::samplers::SampleMinP::new(min_p);
+    }
+    if overrides_defaults {
+        chain +=
+            SampleRepetition::new(request.repeat_penalty.unwrap_or(DEFAULT_REPEAT_PENALTY), 64);
+        chain += SampleTopK::new(40, 1);
+        chain += SampleTopP::new(request.top_p.unwrap_or(DEFAULT_TOP_P), 1);
+        chain += SampleTemperature::new(request.temperature.unwrap_or(DEFAULT_TEMPERATURE));
+        chain += SampleRandDistrib::new();
+    } else {
+        chain += llm::samplers::default_samplers();
+    }
+    Arc::new(Mutex::new(chain))
+}
+
+/// Whether or not the session's text ends with the antiprompt, ignoring
+/// trailing whitespace.
+fn ends_with_antiprompt(session_text: &str, antiprompt: &str) -> bool {
+    session_text.trim_end().ends_with(antiprompt.trim_end())
+}
+
+pub(crate) fn tokenize(
+    model: &dyn llm::Model,
+    text: &str,
+    beginning_of_sentence: bool,
+) -> Result<Vec<llm::TokenId>, InferenceError> {
+    Ok(model
+        .tokenizer()
+        .tokenize(text, beginning_of_sentence)
+        .map_err(|e| InferenceError::custom(e.to_string()))?
+        .into_iter()
+        .map(|(_, id)| id)
+        .collect())
+}
+
+/// Drops tokens from the start of the prompt if it would leave fewer than
+/// `reserve` tokens of the `context_size` tokens available for the response.
+fn truncate_prompt<M: LocalModel + ?Sized>(
+    model: &M,
+    tokens: Vec<llm::TokenId>,
+    context_size: usize,
+    reserve: usize,
+) -> Vec<llm::TokenId> {
+    let budget = context_size.saturating_sub(reserve);
+    if tokens.len() <= budget {
+        return tokens;
+    }
+
+    // Keep the end of the prompt, as that's where the template asks for the response.
+    // The beginning-of-sentence token is preserved if present.
+    let bos = model
+        .bot_token_id()
+        .filter(|bos| tokens.first() == Some(bos));
+    let kept = budget.saturating_sub(bos.is_some() as usize);
+
+    bos.into_iter()
+        .chain(tokens[tokens.len() - kept..].iter().copied())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A model that reads and writes a character per token, and always responds
+    /// with the same text.
+    struct MockModel {
+        response: String,
+        /// How long each token of the response takes to generate
+        delay: Duration,
+        /// If set, the generation fails with this once the prompt has been read
+        error: Option<String>,
+    }
+    impl MockModel {
+        fn responding(response: &str) -> Self {
+            Self {
+                response: response.to_string(),
+                delay: Duration::ZERO,
+                error: None,
+            }
+        }
+    }
+    /// The text the session has been fed and generated.
+    #[derive(Default)]
+    struct MockSession(String);
+
+    impl LocalModel for MockModel {
+        type Session = MockSession;
+
+        fn start_session(&self, _request: &Request) -> MockSession {
+            MockSession::default()
+        }
+
+        fn tokenize(
+            &self,
+            text: &str,
+            _beginning_of_sentence: bool,
+        ) -> Result<Vec<llm::TokenId>, InferenceError> {
+            Ok(text.chars().map(|c| c as llm::TokenId).collect())
+        }
+
+        fn context_size(&self) -> usize {
+            2048
+        }
+
+        fn bot_token_id(&self) -> Option<llm::TokenId> {
+            None
+        }
+
+        fn eot_token_id(&self) -> llm::TokenId {
+            0
+        }
+
+        fn session_length(&self, session: &MockSession) -> usize {
+            session.0.chars().count()
+        }
+
+        fn session_text(&self, session: &MockSession) -> String {
+            session.0.clone()
+        }
+
+        fn infer(
+            &self,
+            session: &mut MockSession,
+            _rng: &mut rand::rngs::StdRng,
+            request: &llm::InferenceRequest,
+            mut callback: impl FnMut(
+                llm::InferenceResponse,
+            ) -> Result<llm::InferenceFeedback, InferenceError>,
+        ) -> Result<(usize, Duration), InferenceError> {
+            let started = Instant::now();
+            if request.play_back_previous_tokens {
+                for c in session.0.chars() {
+                    callback(llm::InferenceResponse::SnapshotToken(c.to_string()))?;
                 }
+            }
+
+            let llm::Prompt::Tokens(prompt) = &request.prompt else { unreachable!("the thread tokenizes prompts itself"); };
+            for &token in prompt.iter() {
+                let c = char::from_u32(token).unwrap();
+                session.0.push(c);
+                callback(llm::InferenceResponse::PromptToken(c.to_string()))?;
+            }
+            if let Some(error) = &self.error {
+                return Err(InferenceError::custom(error));
+            }
 
-                match t {
-                    llm::InferenceResponse::SnapshotToken(t)
-                    | llm::InferenceResponse::PromptToken(t)
-                    | llm::InferenceResponse::InferredToken(t) => request
-                        .token_tx
-                        .send(Token::Token(t))
-                        .map_err(|_| InferenceError::custom("Failed to send token to channel."))?,
-                    llm::InferenceResponse::EotToken => {}
+            let limit = request.maximum_token_count.unwrap_or(usize::MAX);
+            let mut count = 0;
+            for c in self.response.chars().take(limit) {
+                std::thread::sleep(self.delay);
+                session.0.push(c);
+                count += 1;
+                let feedback = callback(llm::InferenceResponse::InferredToken(c.to_string()))?;
+                if matches!(feedback, llm::InferenceFeedback::Halt) {
+                    return Ok((count, started.elapsed()));
                 }
+            }
+            if count == self.response.chars().count() {
+                callback(llm::InferenceResponse::EotToken)?;
+            }
+            Ok((count, started.elapsed()))
+        }
+    }
 
-                Ok(llm::InferenceFeedback::Continue)
-            },
-        )
-        .map(|_| ())
-        .map_err(|e| match e {
-            llm::InferenceError::UserCallback(e) => {
-                e.downcast::<InferenceError>().unwrap().as_ref().clone()
+    /// A generation thread running the model, with the ends of its channels.
+    struct Thread {
+        request_tx: flume::Sender<(Request, flume::Sender<Token>, CancelFlag)>,
+        requests: Requests,
+        shutdown: Arc<AtomicBool>,
+        // Kept so that the thread doesn't see the generator shut down
+        _shutdown_tx: flume::Sender<()>,
+    }
+    impl Thread {
+        fn start(model: MockModel) -> Self {
+            let (request_tx, request_rx) = flume::unbounded();
+            let (shutdown_tx, shutdown_rx) = flume::unbounded();
+            let shutdown = Arc::new(AtomicBool::new(false));
+            make_thread(
+                Arc::new(model),
+                request_rx,
+                Queue::default(),
+                Conversations::<MockSession>::default(),
+                Duration::from_secs(60),
+                shutdown.clone(),
+                shutdown_rx,
+            );
+            Self {
+                request_tx,
+                requests: Requests::default(),
+                shutdown,
+                _shutdown_tx: shutdown_tx,
             }
-            e => InferenceError::custom(e.to_string()),
-        })
+        }
+
+        /// Sends the request to the thread, returning its tokens as they arrive.
+        fn send(&self, request: Request) -> flume::Receiver<Token> {
+            let (token_tx, token_rx) = flume::unbounded();
+            let cancel_flag =
+                CancelFlag::register(&self.requests, request.message_id, &self.shutdown).unwrap();
+            *cancel_flag.state.queued.lock().unwrap() = Some(token_tx.clone());
+            self.request_tx
+                .send((request, token_tx, cancel_flag))
+                .unwrap();
+            token_rx
+        }
+
+        fn cancel(&self, message_id: MessageId) {
+            cancel(&self.requests, message_id);
+        }
+
+        /// Runs the request to completion, returning all of its tokens.
+        fn run(&self, request: Request) -> Vec<Token> {
+            self.send(request).iter().collect()
+        }
+    }
+
+    fn request(prompt: &str) -> Request {
+        Request {
+            model: "mock".to_string(),
+            prompt: prompt.to_string(),
+            batch_size: 8,
+            thread_count: 1,
+            response_token_reserve: 0,
+            stop_sequences: vec![],
+            antiprompt: None,
+            max_tokens: 64,
+            token_biases: vec![],
+            discourage_eos: None,
+            min_p: None,
+            temperature: None,
+            top_p: None,
+            repeat_penalty: None,
+            grammar: None,
+            context_size: None,
+            timeout: None,
+            message_id: MessageId(1),
+            seed: Some(42),
+            conversation: None,
+            play_back_previous_tokens: false,
+            continuation: false,
+        }
+    }
+
+    /// The text of the tokens, including the echoed prompt.
+    fn text(tokens: &[Token]) -> String {
+        tokens
+            .iter()
+            .filter_map(|t| match t {
+                Token::Token(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn is_truncated(tokens: &[Token]) -> bool {
+        tokens.iter().any(|t| matches!(t, Token::Truncated))
+    }
+
+    #[test]
+    fn plays_back_the_conversation_before_the_prompt() {
+        let thread = Thread::start(MockModel::responding(" Fine.\nUser:"));
+        let turn = |prompt: &str, play_back_previous_tokens| Request {
+            antiprompt: Some("\nUser:".to_string()),
+            conversation: Some(ChannelId(1)),
+            play_back_previous_tokens,
+            ..request(prompt)
+        };
+
+        let first = thread.run(turn("User: Hi\nBot:", false));
+        assert_eq!(text(&first), "User: Hi\nBot: Fine.");
+        assert!(!first.iter().any(|t| matches!(t, Token::Replayed(_))));
+
+        let second = thread.run(turn("\nUser: And you?\nBot:", true));
+        let replayed: String = second
+            .iter()
+            .filter_map(|t| match t {
+                Token::Replayed(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        // The antiprompt the first turn stopped at is left for the prompt
+        assert_eq!(replayed, "User: Hi\nBot: Fine.");
+        let replayed_at = second.iter().position(|t| matches!(t, Token::Replayed(_)));
+        let prompt_at = second.iter().position(|t| matches!(t, Token::Token(_)));
+        assert!(replayed_at.unwrap() < prompt_at.unwrap());
+        // The response is still preceded by the whole prompt, so that it can be stripped
+        assert_eq!(text(&second), "\nUser: And you?\nBot: Fine.");
+    }
+
+    #[test]
+    fn completes_the_response() {
+        let thread = Thread::start(MockModel::responding(" world"));
+
+        let tokens = thread.run(request("Hello"));
+
+        assert!(matches!(tokens.first(), Some(Token::Seed(42))));
+        assert_eq!(text(&tokens), "Hello world");
+        assert!(!is_truncated(&tokens));
+        assert!(matches!(tokens.last(), Some(Token::Stats { count: 6, .. })));
+    }
+
+    #[test]
+    fn marks_responses_that_run_out_of_tokens_as_truncated() {
+        let thread = Thread::start(MockModel::responding(" world"));
+
+        let tokens = thread.run(Request {
+            max_tokens: 3,
+            ..request("Hello")
+        });
+
+        assert_eq!(text(&tokens), "Hello wo");
+        assert!(is_truncated(&tokens));
+    }
+
+    #[test]
+    fn stops_at_stop_sequences() {
+        let thread = Thread::start(MockModel::responding(" world. And more"));
+
+        let tokens = thread.run(Request {
+            stop_sequences: vec![". ".to_string()],
+            ..request("Hello")
+        });
+
+        assert_eq!(text(&tokens), "Hello world");
+        assert!(!is_truncated(&tokens));
+    }
+
+    #[test]
+    fn cancels_the_generation() {
+        let thread = Thread::start(MockModel {
+            delay: Duration::from_millis(10),
+            ..MockModel::responding(&" word".repeat(100))
+        });
+
+        let mut tokens = vec![];
+        for token in thread.send(request("")).iter() {
+            if matches!(token, Token::Token(_)) {
+                thread.cancel(MessageId(1));
+            }
+            tokens.push(token);
+        }
+
+        assert!(matches!(
+            tokens.last(),
+            Some(Token::Error(InferenceError::Cancelled))
+        ));
+        assert!(text(&tokens).len() < " word".len() * 100);
+    }
+
+    #[test]
+    fn cancels_queued_requests() {
+        let thread = Thread::start(MockModel {
+            delay: Duration::from_millis(10),
+            ..MockModel::responding(&" word".repeat(50))
+        });
+
+        let running = thread.send(request("Hello"));
+        let queued = thread.send(Request {
+            message_id: MessageId(2),
+            ..request("Hello")
+        });
+        thread.cancel(MessageId(2));
+
+        // The queued request ends straight away, without waiting for its turn
+        assert!(matches!(
+            queued.recv(),
+            Ok(Token::Error(InferenceError::Cancelled))
+        ));
+        assert!(!running.is_disconnected());
+        let running: Vec<_> = running.iter().collect();
+        assert!(matches!(running.last(), Some(Token::Stats { .. })));
+        // And it isn't run once its turn comes
+        assert!(queued.iter().next().is_none());
+    }
+
+    #[test]
+    fn forgets_requests_once_they_end() {
+        let thread = Thread::start(MockModel::responding(" world"));
+
+        thread.run(request("Hello"));
+        // Cancelling a finished request doesn't affect the next one for its message
+        thread.cancel(MessageId(1));
+        let tokens = thread.run(request("Hello"));
+
+        assert_eq!(text(&tokens), "Hello world");
+        assert!(thread.requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_model_errors() {
+        let thread = Thread::start(MockModel {
+            error: Some("the model broke".to_string()),
+            ..MockModel::responding(" world")
+        });
+
+        let tokens = thread.run(request("Hello"));
+
+        match tokens.last() {
+            Some(Token::Error(InferenceError::Custom(message))) => {
+                assert_eq!(message, "the model broke")
+            }
+            _ => panic!("expected the generation to fail"),
+        }
+        // The thread carries on with the next request
+        let tokens = thread.run(request("Hello"));
+        assert!(matches!(
+            tokens.last(),
+            Some(Token::Error(InferenceError::Custom(_)))
+        ));
+    }
+
+    #[test]
+    fn rejects_grammars() {
+        let thread = Thread::start(MockModel::responding(" world"));
+
+        let tokens = thread.run(Request {
+            grammar: Some("root ::= \"yes\"".to_string()),
+            ..request("Hello")
+        });
+
+        match tokens.last() {
+            Some(Token::Error(InferenceError::Custom(message))) => {
+                assert_eq!(message, GRAMMAR_UNSUPPORTED)
+            }
+            _ => panic!("expected the generation to fail"),
+        }
+    }
 }
@@ -1,60 +1,321 @@
 use crate::{
-    config::{self, Configuration},
+    config::{self, Configuration, REPEAT_PENALTY_RANGE, TEMPERATURE_RANGE, TOP_P_RANGE},
     constant,
-    generation::{self, Token},
+    generation::{self, Generator},
+    markdown::chunk_markdown,
+    metrics,
+    output::{self, OutputSink},
+    schedule,
+    stats::Stats,
     util::{self, run_and_report_error, DiscordInteraction},
 };
 use anyhow::Context as AnyhowContext;
 use serenity::{
     async_trait,
-    builder::CreateComponents,
+    builder::{CreateComponents, CreateEmbed},
     client::{Context, EventHandler},
-    futures::StreamExt,
     http::Http,
     model::{
         application::interaction::Interaction,
         prelude::{
             command::{Command, CommandOptionType},
             interaction::{
-                application_command::ApplicationCommandInteraction, InteractionResponseType,
+                application_command::{
+                    ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue,
+                },
+                autocomplete::AutocompleteInteraction,
+                message_component::MessageComponentInteraction,
+                InteractionResponseType,
             },
             *,
         },
     },
+    utils::Colour,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use std::collections::HashSet;
 
+/// The Discord event handler, which registers the configured commands and
+/// responds to them using the generation thread.
 pub struct Handler {
-    _model_thread: std::thread::JoinHandle<()>,
     config: Configuration,
-    request_tx: flume::Sender<generation::Request>,
-    cancel_tx: flume::Sender<MessageId>,
+    generator: Arc<Generator>,
+    recent_generations: RecentGenerations,
+    in_flight_generations: Arc<InFlightGenerations>,
+    cooldowns: Cooldowns,
+    finished_generations: FinishedGenerations,
+    response_cache: ResponseCache,
+    user_generations: UserGenerations,
+    /// The errors of the models that failed to load, keyed by model name
+    model_load_errors: HashMap<String, String>,
+    /// The token biases of each command, resolved to its model's tokens, keyed by
+    /// the command's guild (for guild-specific commands) and name
+    token_biases: HashMap<(Option<u64>, String), Vec<(llm::TokenId, f32)>>,
+    /// The bot's own user, once connected, to recognise mentions of it
+    bot_user_id: OnceLock<UserId>,
+    /// Whether or not an admin has paused generation for maintenance
+    paused: AtomicBool,
+    /// The lifetime totals shown by `/stats`
+    stats: Arc<Stats>,
 }
 impl Handler {
-    pub fn new(config: Configuration, model: Box<dyn llm::Model>) -> Self {
-        let (request_tx, request_rx) = flume::unbounded::<generation::Request>();
-        let (cancel_tx, cancel_rx) = flume::unbounded::<MessageId>();
-
-        let _model_thread = generation::make_thread(model, request_rx, cancel_rx);
+    pub fn new(
+        config: Configuration,
+        models: HashMap<String, Vec<Box<dyn llm::Model>>>,
+        model_load_errors: HashMap<String, String>,
+    ) -> Self {
         Self {
-            _model_thread,
+            recent_generations: RecentGenerations::new(
+                config.administration.recent_generations_capacity,
+            ),
+            in_flight_generations: Arc::new(InFlightGenerations::new(
+                config.inference.max_concurrent_per_user,
+                config.inference.max_queue_size,
+            )),
+            cooldowns: Cooldowns::new(Duration::from_secs(config.inference.user_cooldown_seconds)),
+            finished_generations: FinishedGenerations::default(),
+            response_cache: ResponseCache::new(config.inference.response_cache_size),
+            user_generations: UserGenerations::default(),
+            model_load_errors,
+            token_biases: resolve_token_biases(&config, &models),
+            bot_user_id: OnceLock::new(),
+            paused: AtomicBool::new(false),
+            stats: Arc::new(Stats::load(&config.inference.stats_path)),
+            generator: Arc::new(
+                Generator::new(
+                    models,
+                    Duration::from_secs(config.inference.conversation_idle_timeout_seconds),
+                    config
+                        .inference
+                        .persist_sessions
+                        .then(|| config.inference.session_directory.clone()),
+                )
+                .with_remote_models(remote_models(&config)),
+            ),
             config,
-            request_tx,
-            cancel_tx,
+        }
+    }
+
+    /// Returns a handle that can shut the handler down once the client owns it.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            generator: self.generator.clone(),
+            in_flight_generations: self.in_flight_generations.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// Runs the configured schedules until generation shuts down. Schedules are
+    /// checked at the start of every minute, and each due schedule runs on its own.
+    pub async fn run_schedules(self: Arc<Self>, http: Arc<Http>) {
+        // The expressions were checked when the configuration was loaded
+        let schedules: Vec<_> = self
+            .config
+            .schedules
+            .iter()
+            .filter_map(|(name, s)| Some((name.clone(), schedule::Cron::parse(&s.cron).ok()?)))
+            .collect();
+        if schedules.is_empty() {
+            return;
+        }
+
+        let mut last_minute = None;
+        loop {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let next_minute = now - now % 60 + 60;
+            tokio::time::sleep(Duration::from_secs(next_minute - now)).await;
+            if self.generator.is_stopped() {
+                return;
+            }
+            // The clock can drift from the timer, so a minute may come around twice
+            if last_minute.replace(next_minute) == Some(next_minute) {
+                continue;
+            }
+
+            let time = schedule::UtcTime::from_unix_seconds(next_minute);
+            for (name, cron) in schedules.iter().filter(|(_, cron)| cron.matches(&time)) {
+                let (handler, http, name) = (self.clone(), http.clone(), name.clone());
+                tokio::spawn(async move {
+                    let schedule = &handler.config.schedules[&name];
+                    tracing::info!(schedule = %name, "Running scheduled generation");
+                    if let Err(err) = run_schedule(&http, &handler, schedule).await {
+                        tracing::error!(schedule = %name, "{err:?}");
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// The models in the configuration that are served by other servers.
+fn remote_models(config: &Configuration) -> HashMap<String, generation::RemoteModel> {
+    config
+        .models
+        .iter()
+        .filter(|(_, model)| model.backend == config::Backend::OpenAi)
+        .filter_map(|(name, model)| {
+            Some((
+                name.clone(),
+                generation::RemoteModel {
+                    base_url: model.base_url.clone()?,
+                    api_key: model.api_key.clone(),
+                    model: model.remote_model.clone().unwrap_or_else(|| name.clone()),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Resolves each command's token biases to the tokens of its model, warning about
+/// any that aren't a single token.
+fn resolve_token_biases(
+    config: &Configuration,
+    models: &HashMap<String, Vec<Box<dyn llm::Model>>>,
+) -> HashMap<(Option<u64>, String), Vec<(llm::TokenId, f32)>> {
+    let mut resolved = HashMap::new();
+    for (guild_id, name, command) in config.all_commands() {
+        let model = config
+            .model_name_for(command)
+            .and_then(|model| models.get(model))
+            .and_then(|instances| instances.first());
+        let Some(model) = model else { continue; };
+
+        let biases = command
+            .token_biases
+            .iter()
+            .filter_map(
+                |(text, bias)| match generation::tokenize(model.as_ref(), text, false).as_deref() {
+                    Ok([token]) => Some((*token, *bias)),
+                    _ => {
+                        tracing::warn!(
+                            "{}.token_biases has `{text}`, which is not a single token for its model; it will be ignored",
+                            Configuration::command_path(guild_id, name)
+                        );
+                        None
+                    }
+                },
+            )
+            .collect();
+        resolved.insert((guild_id, name.to_string()), biases);
+    }
+    resolved
+}
+
+/// Winds down a [Handler]: it stops accepting new generations, and stops the ones
+/// in progress with a note about why.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    generator: Arc<Generator>,
+    in_flight_generations: Arc<InFlightGenerations>,
+    stats: Arc<Stats>,
+}
+impl ShutdownHandle {
+    /// Shuts down the generator, then waits for the responses in progress to be
+    /// updated, for up to `timeout`. Conversations are saved once the generator has
+    /// stopped, if they're persisted, and so are the stats.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.in_flight_generations.close();
+        self.generator.shutdown();
+
+        let start = std::time::Instant::now();
+        while !(self.in_flight_generations.is_empty() && self.generator.is_stopped())
+            && start.elapsed() < timeout
+        {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        if self.generator.is_stopped() {
+            if let Err(err) = self.generator.save_conversations() {
+                tracing::error!("Failed to save conversations: {err:?}");
+            }
+        }
+        if let Err(err) = self.stats.save() {
+            tracing::error!("Failed to save stats: {err:?}");
         }
     }
 }
 #[async_trait]
 impl EventHandler for Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
-        println!("{} is connected; registering commands...", ready.user.name);
+        tracing::info!("{} is connected; registering commands...", ready.user.name);
 
         if let Err(err) = ready_handler(&ctx.http, &self.config).await {
-            println!("Error while registering commands: `{err}`");
-            std::process::exit(1);
+            let Some(guidance) = util::missing_access_guidance(&err) else {
+                tracing::error!("Error while registering commands: `{err}`");
+                std::process::exit(1);
+            };
+            // The commands registered on a previous run still work
+            tracing::error!("Error while registering commands: `{err}`. {guidance}");
+            tracing::warn!("Continuing with the commands that are already registered");
+        }
+
+        let fallbacks: Vec<_> = self
+            .config
+            .models
+            .iter()
+            .filter(|(_, model)| model.is_fallback)
+            .map(|(name, model)| format!("{name} ({})", model.path.display()))
+            .collect();
+        if fallbacks.is_empty() {
+            tracing::info!("{} is good to go!", ready.user.name);
+        } else {
+            tracing::warn!(
+                "{} is good to go, but these models are running on their fallbacks: {}",
+                ready.user.name,
+                fallbacks.join(", ")
+            );
+        }
+        self.bot_user_id.set(ready.user.id).ok();
+    }
+
+    async fn message(&self, ctx: Context, msg: Message) {
+        if msg.author.bot {
+            return;
+        }
+        let Some(&bot_user_id) = self.bot_user_id.get() else { return; };
+        let inference = &self.config.inference;
+        let respond = if msg.guild_id.is_none() {
+            // Every direct message is meant for the bot
+            inference.allow_dms
+        } else {
+            inference.respond_to_mentions && msg.mentions_user_id(bot_user_id)
+        };
+        if !respond {
+            return;
+        }
+
+        let prompt = msg
+            .content
+            .replace(&format!("<@{bot_user_id}>"), "")
+            .replace(&format!("<@!{bot_user_id}>"), "");
+        let prompt = prompt.trim();
+        if prompt.is_empty() {
+            return;
+        }
+
+        let http = &ctx.http;
+        if let Err(err) = respond_to_mention(&msg, http, self, prompt.to_string()).await {
+            tracing::error!("{err:?}");
+            msg.reply(http, format!("Error: {err}")).await.ok();
         }
+    }
 
-        println!("{} is good to go!", ready.user.name);
+    async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        if !self.config.inference.allow_cancel {
+            return;
+        }
+        if let Err(err) = cancel_from_reaction(&ctx.http, self, &reaction).await {
+            tracing::error!("{err:?}");
+        }
     }
 
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
@@ -62,37 +323,171 @@ impl EventHandler for Handler {
         match interaction {
             Interaction::ApplicationCommand(cmd) => {
                 let name = cmd.data.name.as_str();
-                let commands = &self.config.commands;
 
-                if let Some(command) = commands.get(name) {
-                    run_and_report_error(
-                        &cmd,
-                        http,
-                        hallucinate(
+                match name {
+                    constant::command::RECENT_GENERATIONS => {
+                        run_and_report_error(
                             &cmd,
                             http,
-                            self.request_tx.clone(),
-                            &self.config.inference,
-                            command,
-                        ),
-                    )
-                    .await;
+                            recent_generations(
+                                &cmd,
+                                http,
+                                &self.config.administration,
+                                &self.recent_generations,
+                            ),
+                        )
+                        .await
+                    }
+                    constant::command::WHOAMI => {
+                        run_and_report_error(&cmd, http, whoami(&cmd, http, &self.config)).await
+                    }
+                    constant::command::HELP => {
+                        run_and_report_error(&cmd, http, help(&cmd, http, &self.config)).await
+                    }
+                    constant::command::STATS => {
+                        run_and_report_error(&cmd, http, stats(&cmd, http, &self.stats)).await
+                    }
+                    constant::command::MODELS => {
+                        run_and_report_error(
+                            &cmd,
+                            http,
+                            models(&cmd, http, &self.config, &self.model_load_errors),
+                        )
+                        .await
+                    }
+                    constant::command::TOKENIZE => {
+                        run_and_report_error(
+                            &cmd,
+                            http,
+                            tokenize(&cmd, http, &self.config, &self.generator),
+                        )
+                        .await
+                    }
+                    constant::command::CANCEL => {
+                        let message = match self.user_generations.latest(cmd.user.id) {
+                            _ if !self.config.inference.allow_cancel => {
+                                "Cancelling generations is disabled."
+                            }
+                            Some(message_id) => {
+                                self.generator.cancel(message_id);
+                                "Your latest generation has been cancelled."
+                            }
+                            None => "You don't have a generation in progress.",
+                        };
+                        run_and_report_error(
+                            &cmd,
+                            http,
+                            util::create_ephemeral_response(&cmd, http, message),
+                        )
+                        .await
+                    }
+                    constant::command::ADMIN => {
+                        run_and_report_error(
+                            &cmd,
+                            http,
+                            admin(&cmd, http, &self.config.administration, &self.paused),
+                        )
+                        .await
+                    }
+                    constant::command::RESET => {
+                        self.generator.reset_conversation(cmd.channel_id);
+                        self.finished_generations
+                            .forget_conversation(cmd.channel_id);
+                        run_and_report_error(
+                            &cmd,
+                            http,
+                            cmd.create(http, "The conversation in this channel has been reset."),
+                        )
+                        .await
+                    }
+                    _ => {
+                        let guild_id = cmd.guild_id.map(|g| g.0);
+                        if let Some((name, command)) = self.config.resolve_command(guild_id, name) {
+                            let role_ids: Vec<u64> = cmd
+                                .member
+                                .iter()
+                                .flat_map(|m| &m.roles)
+                                .map(|r| r.0)
+                                .collect();
+                            if !command.is_allowed(cmd.user.id.0, &role_ids) {
+                                run_and_report_error(
+                                    &cmd,
+                                    http,
+                                    util::create_ephemeral_response(
+                                        &cmd,
+                                        http,
+                                        "You don't have permission to use this command.",
+                                    ),
+                                )
+                                .await;
+                                return;
+                            }
+
+                            run_and_report_error(
+                                &cmd,
+                                http,
+                                hallucinate(&cmd, http, self, name, command),
+                            )
+                            .await;
+                        }
+                    }
+                }
+            }
+            Interaction::Autocomplete(autocomplete) => {
+                let guild_id = autocomplete.guild_id.map(|g| g.0);
+                let result = if autocomplete.data.name == constant::command::WHOAMI {
+                    autocomplete_command_name(&autocomplete, http, &self.config).await
+                } else if let Some(command) = self.config.command(guild_id, &autocomplete.data.name)
+                {
+                    autocomplete_option(&autocomplete, http, command).await
+                } else {
+                    Ok(())
+                };
+                if let Err(err) = result {
+                    tracing::error!("{err:?}");
                 }
             }
             Interaction::MessageComponent(cmp) => {
-                if let ["cancel", message_id, user_id] =
+                if let [action, message_id, user_id] =
                     cmp.data.custom_id.split('#').collect::<Vec<_>>()[..]
                 {
                     if let (Ok(message_id), Ok(user_id)) =
                         (message_id.parse::<u64>(), user_id.parse::<u64>())
                     {
                         if cmp.user.id == user_id {
-                            self.cancel_tx.send(MessageId(message_id)).ok();
-                            cmp.create_interaction_response(http, |r| {
-                                r.kind(InteractionResponseType::DeferredUpdateMessage)
-                            })
-                            .await
-                            .ok();
+                            match action {
+                                // Buttons from before cancelling was disabled are ignored
+                                "cancel" if self.config.inference.allow_cancel => {
+                                    self.generator.cancel(MessageId(message_id));
+                                    cmp.create_interaction_response(http, |r| {
+                                        r.kind(InteractionResponseType::DeferredUpdateMessage)
+                                    })
+                                    .await
+                                    .ok();
+                                }
+                                "regenerate" => {
+                                    run_and_report_error(
+                                        &cmp,
+                                        http,
+                                        regenerate(&cmp, http, self, MessageId(message_id)),
+                                    )
+                                    .await
+                                }
+                                "continue" => {
+                                    run_and_report_error(
+                                        &cmp,
+                                        http,
+                                        continue_generation(
+                                            &cmp,
+                                            http,
+                                            self,
+                                            MessageId(message_id),
+                                        ),
+                                    )
+                                    .await
+                                }
+                                _ => {}
+                            }
                         }
                     }
                 }
@@ -113,7 +508,9 @@ async fn ready_handler(http: &Http, config: &Configuration) -> anyhow::Result<()
         .commands
         .iter()
         .filter(|(_, v)| v.enabled)
-        .map(|(k, _)| k.as_str())
+        .flat_map(|(k, v)| std::iter::once(k).chain(&v.aliases))
+        .map(|k| k.as_str())
+        .chain(constant::command::ALL.iter().copied())
         .collect();
 
     if registered_commands != our_commands {
@@ -124,282 +521,3077 @@ async fn ready_handler(http: &Http, config: &Configuration) -> anyhow::Result<()
     }
 
     for (name, command) in config.commands.iter().filter(|(_, v)| v.enabled) {
-        Command::create_global_application_command(http, |cmd| {
-            cmd.name(name)
-                .description(command.description.as_str())
-                .create_option(|opt| {
-                    opt.name(constant::value::PROMPT)
-                        .description("The prompt.")
-                        .kind(CommandOptionType::String)
-                        .required(true)
-                });
+        for name in std::iter::once(name).chain(&command.aliases) {
+            Command::create_global_application_command(http, |cmd| {
+                create_command(cmd, name, command, &config.inference)
+            })
+            .await?;
+        }
+    }
 
-            create_parameters(cmd)
-        })
-        .await?;
+    register_builtin_commands(http, config).await?;
+
+    // Each guild's own commands are reconciled in the same way as the global ones.
+    // A guild the bot can't register commands in shouldn't stop the others.
+    for (guild_id, commands) in config.guild_commands() {
+        if let Err(err) =
+            register_guild_commands(http, GuildId(guild_id), commands, &config.inference).await
+        {
+            let Some(guidance) = util::missing_access_guidance(&err) else { return Err(err); };
+            tracing::error!(
+                guild_id,
+                "Error while registering the guild's commands: `{err}`. {guidance}"
+            );
+        }
     }
 
     Ok(())
 }
 
-fn create_parameters(
-    command: &mut serenity::builder::CreateApplicationCommand,
-) -> &mut serenity::builder::CreateApplicationCommand {
-    command.create_option(|opt| {
-        opt.name(constant::value::SEED)
-            .kind(CommandOptionType::Integer)
-            .description("The seed to use for sampling.")
-            .min_int_value(0)
-            .required(false)
-    })
-}
-
-async fn hallucinate(
-    cmd: &ApplicationCommandInteraction,
+async fn register_guild_commands(
     http: &Http,
-    request_tx: flume::Sender<generation::Request>,
+    guild_id: GuildId,
+    commands: &HashMap<String, config::Command>,
     inference: &config::Inference,
-    command: &config::Command,
 ) -> anyhow::Result<()> {
-    use constant::value as v;
-    use util::{value_to_integer, value_to_string};
-
-    let options = &cmd.data.options;
-    let user_prompt = util::get_value(options, v::PROMPT)
-        .and_then(value_to_string)
-        .context("no prompt specified")?;
-
-    let user_prompt = if inference.replace_newlines {
-        user_prompt.replace("\\n", "\n")
-    } else {
-        user_prompt
-    };
-
-    let mut outputter = Outputter::new(
-        http,
-        cmd,
-        Prompts {
-            show_prompt_template: inference.show_prompt_template,
-            processed: command.prompt.replace("{{PROMPT}}", &user_prompt),
-            user: user_prompt,
-            template: command.prompt.clone(),
-        },
-        std::time::Duration::from_millis(inference.discord_message_update_interval_ms),
-    )
-    .await?;
-
-    let message = cmd.get_interaction_message(http).await?;
-    let message_id = message.id;
-
-    let seed = util::get_value(options, v::SEED)
-        .and_then(value_to_integer)
-        .map(|i| i as u64);
+    let registered_commands = guild_id.get_application_commands(http).await?;
+    let registered_commands: HashSet<_> = registered_commands
+        .iter()
+        .map(|c| c.name.as_str())
+        .collect();
 
-    let (token_tx, token_rx) = flume::unbounded();
-    request_tx.send(generation::Request {
-        prompt: outputter.prompts.processed.clone(),
-        batch_size: inference.batch_size,
-        token_tx,
-        message_id,
-        seed,
-    })?;
+    let our_commands: HashSet<_> = commands
+        .iter()
+        .filter(|(_, v)| v.enabled)
+        .flat_map(|(k, v)| std::iter::once(k).chain(&v.aliases))
+        .map(|k| k.as_str())
+        .collect();
 
-    let mut stream = token_rx.into_stream();
+    if registered_commands != our_commands {
+        guild_id
+            .set_application_commands(http, |c| c.set_application_commands(vec![]))
+            .await?;
+    }
 
-    let mut errored = false;
-    while let Some(token) = stream.next().await {
-        match token {
-            Token::Token(t) => {
-                outputter.new_token(&t).await?;
-            }
-            Token::Error(err) => {
-                match err {
-                    generation::InferenceError::Cancelled => outputter.cancelled().await?,
-                    generation::InferenceError::Custom(m) => outputter.error(&m).await?,
-                };
-                errored = true;
-                break;
-            }
+    for (name, command) in commands.iter().filter(|(_, v)| v.enabled) {
+        for name in std::iter::once(name).chain(&command.aliases) {
+            guild_id
+                .create_application_command(http, |cmd| {
+                    create_command(cmd, name, command, inference)
+                })
+                .await?;
         }
     }
-    if !errored {
-        outputter.finish().await?;
-    }
 
     Ok(())
 }
 
-struct Prompts {
-    show_prompt_template: bool,
+/// Describes a configured command to Discord.
+fn create_command<'a>(
+    cmd: &'a mut serenity::builder::CreateApplicationCommand,
+    name: &str,
+    command: &config::Command,
+    inference: &config::Inference,
+) -> &'a mut serenity::builder::CreateApplicationCommand {
+    cmd.name(name).description(command.description.as_str());
 
-    processed: String,
-    user: String,
-    template: String,
-}
-impl Prompts {
-    fn make_markdown_message(&self, message: &str) -> String {
-        let (message, display_prompt) = if !self.show_prompt_template {
-            (self.decouple_prompt_from_message(message), &self.user)
-        } else {
-            (message.to_string(), &self.processed)
-        };
+    // Discord requires the required options to come before the rest
+    for option in command.options.iter().filter(|o| o.required) {
+        create_extra_option(cmd, option);
+    }
+    cmd.create_option(|opt| {
+        opt.name(constant::value::PROMPT)
+            .description("The prompt. Required unless a file is attached.")
+            .kind(CommandOptionType::String)
+            .required(false)
+    })
+    .create_option(|opt| {
+        opt.name(constant::value::FILE)
+            .description("A text file to use as the prompt, after the prompt if there is one.")
+            .kind(CommandOptionType::Attachment)
+            .required(false)
+    });
+    for option in command.options.iter().filter(|o| !o.required) {
+        create_extra_option(cmd, option);
+    }
 
-        match message.strip_prefix(display_prompt) {
-            Some(msg) => format!("**{display_prompt}**{msg}"),
-            None => match display_prompt.strip_prefix(&message) {
-                Some(ungenerated) => {
-                    if message.is_empty() {
-                        format!("~~{ungenerated}~~")
-                    } else {
-                        format!("**{message}**~~{ungenerated}~~")
-                    }
-                }
-                None => message.to_string(),
-            },
-        }
+    let cmd = create_parameters(cmd, inference);
+    if !command.presets.is_empty() {
+        cmd.create_option(|opt| {
+            opt.name(constant::value::PRESET)
+                .kind(CommandOptionType::String)
+                .description("A preset to add to the prompt.")
+                .set_autocomplete(true)
+                .required(false)
+        });
     }
+    cmd
+}
 
-    fn decouple_prompt_from_message(&self, output: &str) -> String {
-        let (prefix, suffix) = self.template.split_once("{{PROMPT}}").unwrap_or_default();
+async fn register_builtin_commands(http: &Http, config: &Configuration) -> anyhow::Result<()> {
+    Command::create_global_application_command(http, |cmd| {
+        cmd.name(constant::command::RECENT_GENERATIONS)
+            .description("Lists the most recent generations and their parameters (admin only).")
+    })
+    .await?;
 
-        let prompt = &self.user;
+    Command::create_global_application_command(http, |cmd| {
+        cmd.name(constant::command::ADMIN)
+            .description("Administers the bot (admin only).")
+            .create_option(|opt| {
+                opt.name(constant::value::PAUSE)
+                    .description("Stops new generations until generation is resumed.")
+                    .kind(CommandOptionType::SubCommand)
+            })
+            .create_option(|opt| {
+                opt.name(constant::value::RESUME)
+                    .description("Allows new generations again.")
+                    .kind(CommandOptionType::SubCommand)
+            })
+    })
+    .await?;
 
-        let Some(message) = output.strip_prefix(prefix) else { return String::new(); };
-        let Some(response) = message.strip_prefix(prompt) else { return message.to_string(); };
-        let Some(response) = response.strip_prefix(suffix) else { return prompt.to_string(); };
+    Command::create_global_application_command(http, |cmd| {
+        cmd.name(constant::command::RESET)
+            .description("Forgets the conversation in this channel.")
+    })
+    .await?;
 
-        let newline = if suffix.ends_with('\n') { "\n" } else { "" };
+    Command::create_global_application_command(http, |cmd| {
+        cmd.name(constant::command::CANCEL)
+            .description("Cancels your latest generation in progress.")
+    })
+    .await?;
 
-        format!("{prompt}{newline}{response}")
-    }
-}
+    Command::create_global_application_command(http, |cmd| {
+        cmd.name(constant::command::HELP)
+            .description("Lists the commands available here and their parameters.")
+    })
+    .await?;
 
-struct Outputter<'a> {
-    http: &'a Http,
+    Command::create_global_application_command(http, |cmd| {
+        cmd.name(constant::command::STATS)
+            .description("Shows how long the bot has been up and how much it has generated.")
+    })
+    .await?;
 
-    user_id: UserId,
-    messages: Vec<Message>,
-    chunks: Vec<String>,
+    Command::create_global_application_command(http, |cmd| {
+        cmd.name(constant::command::MODELS)
+            .description("Lists the models available to commands.")
+    })
+    .await?;
 
-    message: String,
-    prompts: Prompts,
+    Command::create_global_application_command(http, |cmd| {
+        cmd.name(constant::command::TOKENIZE)
+            .description("Shows how a model tokenizes some text.")
+            .create_option(|opt| {
+                opt.name(constant::value::TEXT)
+                    .description("The text to tokenize.")
+                    .kind(CommandOptionType::String)
+                    .required(true)
+            })
+            .create_option(|opt| {
+                opt.name(constant::value::MODEL)
+                    .description("The model to tokenize with.")
+                    .kind(CommandOptionType::String)
+                    .required(config.models.len() > 1);
+                for name in config.models.keys() {
+                    opt.add_string_choice(name, name);
+                }
+                opt
+            })
+            .create_option(|opt| {
+                opt.name(constant::value::SHOW_TOKENS)
+                    .description("Whether or not to show the individual tokens.")
+                    .kind(CommandOptionType::Boolean)
+                    .required(false)
+            })
+    })
+    .await?;
 
-    in_terminal_state: bool,
+    Command::create_global_application_command(http, |cmd| {
+        cmd.name(constant::command::WHOAMI)
+            .description("Shows the settings that apply to you here.")
+            .create_option(|opt| {
+                // Autocompleted rather than given choices, as the commands differ by guild
+                opt.name(constant::value::COMMAND)
+                    .description("The command to show the settings of.")
+                    .kind(CommandOptionType::String)
+                    .set_autocomplete(true)
+                    .required(false)
+            })
+    })
+    .await?;
 
-    last_update: std::time::Instant,
-    last_update_duration: std::time::Duration,
+    Ok(())
 }
-impl<'a> Outputter<'a> {
-    const MESSAGE_CHUNK_SIZE: usize = 1500;
 
-    async fn new(
-        http: &'a Http,
-        cmd: &ApplicationCommandInteraction,
-        prompts: Prompts,
-        last_update_duration: std::time::Duration,
-    ) -> anyhow::Result<Outputter<'a>> {
-        cmd.create_interaction_response(http, |response| {
-            response
-                .kind(InteractionResponseType::ChannelMessageWithSource)
-                .interaction_response_data(|message| {
-                    message
-                        .content(format!(
-                            "~~{}~~",
-                            if prompts.show_prompt_template {
-                                &prompts.processed
-                            } else {
-                                &prompts.user
-                            }
-                        ))
-                        .allowed_mentions(|m| m.empty_roles().empty_users().empty_parse())
-                })
+fn create_parameters<'a>(
+    command: &'a mut serenity::builder::CreateApplicationCommand,
+    inference: &config::Inference,
+) -> &'a mut serenity::builder::CreateApplicationCommand {
+    command
+        .create_option(|opt| {
+            opt.name(constant::value::SEED)
+                .kind(CommandOptionType::Integer)
+                .description("The seed to use for sampling.")
+                .min_int_value(0)
+                .required(false)
         })
-        .await?;
-        let starting_message = cmd.get_interaction_response(http).await?;
+        .create_option(|opt| {
+            opt.name(constant::value::STOP_SEQUENCE)
+                .kind(CommandOptionType::String)
+                .description("Stop generating when this text is produced.")
+                .required(false)
+        })
+        .create_option(|opt| {
+            opt.name(constant::value::MAX_TOKENS)
+                .kind(CommandOptionType::Integer)
+                .description("The maximum number of tokens to generate.")
+                .min_int_value(1)
+                .max_int_value(inference.max_tokens as u64)
+                .required(false)
+        })
+        .create_option(|opt| {
+            opt.name(constant::value::BATCH_SIZE)
+                .kind(CommandOptionType::Integer)
+                .description("The number of prompt tokens to process at once.")
+                .min_int_value(1)
+                .max_int_value(max_batch_size(inference) as u64)
+                .required(false)
+        })
+        .create_option(|opt| {
+            opt.name(constant::value::TEMPERATURE)
+                .kind(CommandOptionType::Number)
+                .description("How random the response is. Higher values are more creative.")
+                .min_number_value(*TEMPERATURE_RANGE.start())
+                .max_number_value(*TEMPERATURE_RANGE.end())
+                .required(false)
+        })
+        .create_option(|opt| {
+            opt.name(constant::value::CREATIVITY)
+                .kind(CommandOptionType::String)
+                .description(
+                    "How creative the response is. An explicit temperature takes precedence.",
+                )
+                .required(false);
+            for (name, ..) in CREATIVITY_LEVELS {
+                opt.add_string_choice(name, name);
+            }
+            opt
+        })
+        .create_option(|opt| {
+            opt.name(constant::value::REPEAT_PENALTY)
+                .kind(CommandOptionType::Number)
+                .description("How much to discourage repetition. 1 is no penalty.")
+                .min_number_value(*REPEAT_PENALTY_RANGE.start())
+                .max_number_value(*REPEAT_PENALTY_RANGE.end())
+                .required(false)
+        })
+        .create_option(|opt| {
+            opt.name(constant::value::RAW_NEWLINES)
+                .kind(CommandOptionType::Boolean)
+                .description(
+                    "Whether or not to keep '\\n' as-is instead of replacing it with a newline.",
+                )
+                .required(false)
+        })
+        .create_option(|opt| {
+            opt.name(constant::value::DRY_RUN)
+                .kind(CommandOptionType::Boolean)
+                .description("Show the prompt that would be given to the model without generating.")
+                .required(false)
+        })
+        .create_option(|opt| {
+            opt.name(constant::value::LANGUAGE)
+                .kind(CommandOptionType::String)
+                .description("The language to respond in.")
+                .set_autocomplete(true)
+                .required(false)
+        })
+}
 
-        Ok(Self {
-            http,
+/// The languages suggested for the `language` option. Users can type any other.
+const LANGUAGES: &[&str] = &[
+    "Arabic",
+    "Chinese",
+    "Dutch",
+    "English",
+    "French",
+    "German",
+    "Hindi",
+    "Indonesian",
+    "Italian",
+    "Japanese",
+    "Korean",
+    "Polish",
+    "Portuguese",
+    "Russian",
+    "Spanish",
+    "Swedish",
+    "Turkish",
+    "Ukrainian",
+    "Vietnamese",
+];
+/// Language names are short, so anything longer is probably an attempt to inject
+/// a prompt.
+const MAX_LANGUAGE_LENGTH: usize = 32;
+
+/// The choices for the `creativity` option, as their name, temperature and top-p.
+/// Balanced is llm's default.
+const CREATIVITY_LEVELS: &[(&str, f32, f32)] = &[
+    ("Precise", 0.3, 0.8),
+    ("Balanced", 0.8, 0.95),
+    ("Creative", 1.2, 1.0),
+];
+
+/// Reads a number parameter, clamping it to its range. Discord enforces the
+/// range it was registered with, but that can be out of date.
+fn clamped_number(
+    options: &[CommandDataOption],
+    name: &str,
+    range: std::ops::RangeInclusive<f64>,
+) -> Option<f32> {
+    let value = util::get_value(options, name).and_then(util::value_to_number)?;
+    let clamped = value.clamp(*range.start(), *range.end());
+    if clamped != value {
+        tracing::debug!(
+            parameter = name,
+            value,
+            clamped,
+            "Clamped out-of-range parameter"
+        );
+    }
+    Some(clamped as f32)
+}
 
-            user_id: cmd.user.id,
-            messages: vec![starting_message],
-            chunks: vec![],
+/// A command's default for a number parameter, clamped to the range users can ask for.
+fn clamped_default(value: Option<f32>, range: std::ops::RangeInclusive<f64>) -> Option<f32> {
+    value.map(|value| (value as f64).clamp(*range.start(), *range.end()) as f32)
+}
 
-            message: String::new(),
-            prompts,
+/// Describes one of a command's extra text inputs to Discord.
+fn create_extra_option(
+    cmd: &mut serenity::builder::CreateApplicationCommand,
+    option: &config::CommandOption,
+) {
+    cmd.create_option(|opt| {
+        opt.name(&option.name)
+            .description(&option.description)
+            .kind(CommandOptionType::String)
+            .required(option.required)
+    });
+}
 
-            in_terminal_state: false,
+/// The largest batch size users can ask for. Larger batches use more memory
+/// without speeding up prompt processing much.
+fn max_batch_size(inference: &config::Inference) -> usize {
+    inference.batch_size.max(512)
+}
 
-            last_update: std::time::Instant::now(),
-            last_update_duration,
-        })
-    }
+/// The reply to generation requests while an admin has paused generation
+const PAUSED_MESSAGE: &str = "The bot is temporarily paused for maintenance.";
 
-    async fn new_token(&mut self, token: &str) -> anyhow::Result<()> {
-        if self.in_terminal_state {
-            return Ok(());
-        }
+async fn hallucinate(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    handler: &Handler,
+    command_name: &str,
+    command: &config::Command,
+) -> anyhow::Result<()> {
+    use constant::value as v;
+    use util::{value_to_bool, value_to_integer, value_to_string};
 
-        if self.message.is_empty() {
-            // Add the cancellation button when we receive the first token
-            if let Some(first) = self.messages.first_mut() {
-                add_cancel_button(self.http, first.id, first, self.user_id).await?;
-            }
-        }
+    // Deferred before anything slow, like downloading attachments or fetching
+    // history, happens. Webhook responses are posted separately, so only the
+    // invoking user sees the interaction's own response.
+    let ephemeral = command.ephemeral || command.webhook_username.is_some();
+    util::defer_response(cmd, http, ephemeral).await?;
 
-        self.message += token;
+    if handler.paused.load(Ordering::SeqCst) {
+        return util::create_deferred_ephemeral_response(cmd, http, ephemeral, PAUSED_MESSAGE)
+            .await;
+    }
 
-        // This could be much more efficient but that's a problem for later
-        self.chunks = {
-            let mut chunks: Vec<String> = vec![];
+    let options = &cmd.data.options;
+    let dry_run = util::get_value(options, v::DRY_RUN)
+        .and_then(value_to_bool)
+        .unwrap_or(false);
+    let text_prompt = util::get_value(options, v::PROMPT).and_then(value_to_string);
+    let file_prompt = match util::get_value(options, v::FILE) {
+        Some(CommandDataOptionValue::Attachment(attachment)) => {
+            match read_prompt_attachment(attachment, handler.config.inference.max_attachment_bytes)
+                .await?
+            {
+                Ok(text) => Some(text),
+                Err(message) => {
+                    return util::create_deferred_ephemeral_response(cmd, http, ephemeral, &message)
+                        .await
+                }
+            }
+        }
+        _ => None,
+    };
+    let user_prompt = match (text_prompt, file_prompt) {
+        (Some(text), Some(file)) => format!("{text}\n\n{file}"),
+        (Some(prompt), None) | (None, Some(prompt)) => prompt,
+        (None, None) => {
+            return util::create_deferred_ephemeral_response(
+                cmd,
+                http,
+                ephemeral,
+                "Please give a prompt, or attach a text file to use as the prompt.",
+            )
+            .await
+        }
+    };
+    if let Some(message) = check_prompt_chars(command, &user_prompt) {
+        return util::create_deferred_ephemeral_response(cmd, http, ephemeral, &message).await;
+    }
+
+    // Held until the end of the generation, however it ends
+    let _in_flight = match handler.in_flight_generations.try_start(cmd.user.id) {
+        Ok(guard) => guard,
+        Err(message) => {
+            return util::create_deferred_ephemeral_response(cmd, http, ephemeral, &message).await
+        }
+    };
+
+    let inference = &handler.config.inference;
+
+    if let Some(err) = model_load_error(handler, command) {
+        return util::create_deferred_ephemeral_response(
+            cmd,
+            http,
+            ephemeral,
+            &format!("The model failed to load: {err}"),
+        )
+        .await;
+    }
+
+    let preset = match util::get_value(options, v::PRESET).and_then(value_to_string) {
+        Some(name) => match command.presets.get(&name) {
+            Some(preset) => Some(preset.clone()),
+            None => {
+                return util::create_deferred_ephemeral_response(
+                    cmd,
+                    http,
+                    ephemeral,
+                    &format!("There is no preset named `{name}`."),
+                )
+                .await
+            }
+        },
+        None => None,
+    };
+
+    let replace_newlines = util::get_value(options, v::RAW_NEWLINES)
+        .and_then(value_to_bool)
+        .map_or(inference.replace_newlines, |raw| !raw);
+
+    let user_prompt = if replace_newlines {
+        user_prompt.replace("\\n", "\n")
+    } else {
+        user_prompt
+    };
+
+    let mut extra_options = HashMap::new();
+    for option in &command.options {
+        match util::get_value(options, &option.name).and_then(value_to_string) {
+            Some(value) if replace_newlines => {
+                extra_options.insert(option.name.clone(), value.replace("\\n", "\n"));
+            }
+            Some(value) => {
+                extra_options.insert(option.name.clone(), value);
+            }
+            None if option.required => {
+                return util::create_deferred_ephemeral_response(
+                    cmd,
+                    http,
+                    ephemeral,
+                    &format!("Please fill in `{}`.", option.name),
+                )
+                .await
+            }
+            None => {}
+        }
+    }
+
+    let history = if command.uses_history() {
+        fetch_history(http, cmd.channel_id, command.history_length).await?
+    } else {
+        String::new()
+    };
+
+    let chosen_seed = util::get_value(options, v::SEED)
+        .and_then(value_to_integer)
+        .map(|i| i as u64)
+        .or(command.defaults.seed);
+    // Resolved here rather than by the generator, as the prompt variant depends on it
+    let seed = chosen_seed.unwrap_or_else(rand::random);
+
+    let max_tokens = util::get_value(options, v::MAX_TOKENS)
+        .and_then(value_to_integer)
+        .map_or(command.default_max_tokens(inference), |i| {
+            (i.max(1) as usize).min(inference.max_tokens)
+        });
+
+    let batch_size = util::get_value(options, v::BATCH_SIZE)
+        .and_then(value_to_integer)
+        .map_or(inference.batch_size, |i| {
+            (i.max(1) as usize).min(max_batch_size(inference))
+        });
+
+    let creativity = util::get_value(options, v::CREATIVITY)
+        .and_then(value_to_string)
+        .and_then(|choice| CREATIVITY_LEVELS.iter().find(|(name, ..)| *name == choice));
+    let defaults = &command.defaults;
+    let temperature = clamped_number(options, v::TEMPERATURE, TEMPERATURE_RANGE)
+        .or(creativity.map(|(_, temperature, _)| *temperature))
+        .or(clamped_default(defaults.temperature, TEMPERATURE_RANGE));
+    let top_p = creativity
+        .map(|(_, _, top_p)| *top_p)
+        .or(clamped_default(defaults.top_p, TOP_P_RANGE));
+    let repeat_penalty = clamped_number(options, v::REPEAT_PENALTY, REPEAT_PENALTY_RANGE).or(
+        clamped_default(defaults.repeat_penalty, REPEAT_PENALTY_RANGE),
+    );
+
+    let language = util::get_value(options, v::LANGUAGE)
+        .and_then(value_to_string)
+        .map(|language| language.trim().to_string())
+        .filter(|language| !language.is_empty() && language.len() <= MAX_LANGUAGE_LENGTH);
+
+    let mut stop_sequences = command.stop_sequences.clone();
+    if command.stop_on_double_newline {
+        stop_sequences.push("\n\n".to_string());
+    }
+    if let Some(stop_sequence) =
+        util::get_value(options, v::STOP_SEQUENCE).and_then(value_to_string)
+    {
+        stop_sequences.push(if replace_newlines {
+            stop_sequence.replace("\\n", "\n")
+        } else {
+            stop_sequence
+        });
+    }
+
+    let generation = Generation {
+        command_name: command_name.to_string(),
+        guild_id: cmd.guild_id.map(|g| g.0),
+        user_prompt,
+        preset,
+        options: extra_options,
+        history,
+        language,
+        variant: command.pick_variant(seed).map(|v| v.name.clone()),
+        seed,
+        seed_chosen: chosen_seed.is_some(),
+        max_tokens,
+        batch_size,
+        temperature,
+        top_p,
+        repeat_penalty,
+        stop_sequences,
+        parameters: options
+            .iter()
+            .filter(|o| o.name != v::PROMPT && o.name != v::SEED)
+            .map(|o| {
+                let value = o.value.as_ref().map(|value| value.to_string());
+                format!("{}={}", o.name, value.unwrap_or_default())
+            })
+            .collect(),
+    };
+
+    let prompts = Prompts::new(command, inference, &generation);
+    if dry_run {
+        return util::create_deferred_ephemeral_response(
+            cmd,
+            http,
+            ephemeral,
+            &dry_run_message(&prompts.processed),
+        )
+        .await;
+    }
+    if let Some(message) = check_prompt_length(handler, command, &prompts)? {
+        return util::create_deferred_ephemeral_response(cmd, http, ephemeral, &message).await;
+    }
+    // Started last, so that requests that were turned away (and dry runs, which
+    // don't use the model) don't count towards the cooldown
+    if let Err(message) = handler.cooldowns.try_start(cmd.user.id) {
+        return util::create_deferred_ephemeral_response(cmd, http, ephemeral, &message).await;
+    }
+
+    let outputter = Outputter::new(http, cmd, prompts, inference, command).await?;
+
+    run_generation(
+        http, handler, command, generation, outputter, &cmd.user, false,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Suggests values for the option the user is filling in: the command's presets or
+/// languages whose names contain what the user has typed so far.
+async fn autocomplete_option(
+    autocomplete: &AutocompleteInteraction,
+    http: &Http,
+    command: &config::Command,
+) -> anyhow::Result<()> {
+    let Some(focused) = autocomplete.data.options.iter().find(|o| o.focused) else { return Ok(()); };
+    let typed = focused
+        .value
+        .as_ref()
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    let mut names: Vec<&str> = match focused.name.as_str() {
+        constant::value::PRESET => command.presets.keys().map(String::as_str).collect(),
+        constant::value::LANGUAGE => LANGUAGES.to_vec(),
+        _ => return Ok(()),
+    };
+    names.retain(|name| name.to_lowercase().contains(&typed));
+    names.sort();
+
+    // Discord shows at most 25 suggestions
+    autocomplete
+        .create_autocomplete_response(http, |r| {
+            for name in names.into_iter().take(25) {
+                r.add_string_choice(name, name);
+            }
+            r
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Suggests the commands that can be used where `/whoami` was run, whose names
+/// contain what the user has typed so far.
+async fn autocomplete_command_name(
+    autocomplete: &AutocompleteInteraction,
+    http: &Http,
+    config: &Configuration,
+) -> anyhow::Result<()> {
+    let Some(focused) = autocomplete.data.options.iter().find(|o| o.focused) else { return Ok(()); };
+    let typed = focused
+        .value
+        .as_ref()
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    let guild_id = autocomplete.guild_id.map(|g| g.0);
+    let guild_commands = guild_id
+        .and_then(|id| config.guilds.get(&id.to_string()))
+        .map(|guild| &guild.commands);
+    let mut names: Vec<&str> = config
+        .commands
+        .keys()
+        .chain(
+            guild_commands
+                .into_iter()
+                .flat_map(|commands| commands.keys()),
+        )
+        .map(String::as_str)
+        .filter(|name| config.command(guild_id, name).is_some_and(|c| c.enabled))
+        .filter(|name| name.to_lowercase().contains(&typed))
+        .collect();
+    names.sort();
+    names.dedup();
+
+    // Discord shows at most 25 suggestions
+    autocomplete
+        .create_autocomplete_response(http, |r| {
+            for name in names.into_iter().take(25) {
+                r.add_string_choice(name, name);
+            }
+            r
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Runs the schedule's command and posts the response in its channel, as the bot.
+async fn run_schedule(
+    http: &Http,
+    handler: &Handler,
+    schedule: &config::Schedule,
+) -> anyhow::Result<()> {
+    if handler.paused.load(Ordering::SeqCst) {
+        tracing::info!("Skipping scheduled generation while paused");
+        return Ok(());
+    }
+
+    let inference = &handler.config.inference;
+    let command_name = schedule.command.as_str();
+    let command = handler
+        .config
+        .commands
+        .get(command_name)
+        .with_context(|| format!("no command named `{command_name}`"))?;
+    if let Some(err) = model_load_error(handler, command) {
+        anyhow::bail!("the model failed to load: {err}");
+    }
+
+    let channel_id = ChannelId(schedule.channel_id);
+    let history = if command.uses_history() {
+        fetch_history(http, channel_id, command.history_length).await?
+    } else {
+        String::new()
+    };
+    let generation = Generation::with_defaults(
+        command_name,
+        command,
+        inference,
+        schedule.prompt.clone(),
+        history,
+    );
+
+    let prompts = Prompts::new(command, inference, &generation);
+    if let Some(message) = check_prompt_length(handler, command, &prompts)? {
+        anyhow::bail!("{message}");
+    }
+
+    let user = User::from(http.get_current_user().await?);
+    let mut outputter =
+        Outputter::for_channel(http, channel_id, user.id, prompts, inference, command).await?;
+    // Only the bot could press the cancel button
+    outputter.allow_cancel = false;
+    run_generation(http, handler, command, generation, outputter, &user, false).await?;
+
+    Ok(())
+}
+
+/// Responds to a message that mentions the bot or was sent to it directly, using
+/// `user_prompt` (the rest of the message) as the prompt for `inference.default_command`.
+async fn respond_to_mention(
+    msg: &Message,
+    http: &Http,
+    handler: &Handler,
+    user_prompt: String,
+) -> anyhow::Result<()> {
+    if handler.paused.load(Ordering::SeqCst) {
+        msg.reply(http, PAUSED_MESSAGE).await?;
+        return Ok(());
+    }
+
+    let inference = &handler.config.inference;
+    let command_name = inference
+        .default_command
+        .as_deref()
+        .context("inference.default_command is not set")?;
+    let command = handler
+        .config
+        .commands
+        .get(command_name)
+        .with_context(|| format!("no command named `{command_name}`"))?;
+
+    // Direct messages have no member, so only `allowed_users` applies to them
+    let role_ids: Vec<u64> = msg
+        .member
+        .iter()
+        .flat_map(|m| &m.roles)
+        .map(|r| r.0)
+        .collect();
+    if !command.is_allowed(msg.author.id.0, &role_ids) {
+        msg.reply(http, "You don't have permission to use this command.")
+            .await?;
+        return Ok(());
+    }
+    if let Some(message) = check_prompt_chars(command, &user_prompt) {
+        msg.reply(http, message).await?;
+        return Ok(());
+    }
+
+    // Held until the end of the generation, however it ends
+    let _in_flight = match handler.in_flight_generations.try_start(msg.author.id) {
+        Ok(guard) => guard,
+        Err(message) => {
+            msg.reply(http, message).await?;
+            return Ok(());
+        }
+    };
+
+    if let Some(err) = model_load_error(handler, command) {
+        msg.reply(http, format!("The model failed to load: {err}"))
+            .await?;
+        return Ok(());
+    }
+
+    let history = if command.uses_history() {
+        fetch_history(http, msg.channel_id, command.history_length).await?
+    } else {
+        String::new()
+    };
+
+    let generation =
+        Generation::with_defaults(command_name, command, inference, user_prompt, history);
+
+    let prompts = Prompts::new(command, inference, &generation);
+    if let Some(message) = check_prompt_length(handler, command, &prompts)? {
+        msg.reply(http, message).await?;
+        return Ok(());
+    }
+    if let Err(message) = handler.cooldowns.try_start(msg.author.id) {
+        msg.reply(http, message).await?;
+        return Ok(());
+    }
+
+    let reaction = StatusReaction::start(http, msg, inference.status_reactions).await;
+    let result = match Outputter::for_reply(http, msg, prompts, inference, command).await {
+        Ok(outputter) => {
+            run_generation(
+                http,
+                handler,
+                command,
+                generation,
+                outputter,
+                &msg.author,
+                false,
+            )
+            .await
+        }
+        Err(err) => Err(err),
+    };
+    reaction.finish(matches!(result, Ok(true))).await;
+    result?;
+
+    Ok(())
+}
+
+/// Cancels the generation the reaction was added to the response of, if the reaction
+/// is [STOP_REACTION] and the user who added it started the generation.
+async fn cancel_from_reaction(
+    http: &Http,
+    handler: &Handler,
+    reaction: &Reaction,
+) -> anyhow::Result<()> {
+    // Long responses are split into messages that each reply to the one before
+    const MAX_RESPONSE_MESSAGES: usize = 10;
+
+    let ReactionType::Unicode(emoji) = &reaction.emoji else { return Ok(()); };
+    // The emoji may or may not come with its variation selector
+    if emoji.trim_end_matches('\u{fe0f}') != STOP_REACTION {
+        return Ok(());
+    }
+    let Some(user_id) = reaction.user_id else { return Ok(()); };
+    if handler.user_generations.latest(user_id).is_none() {
+        return Ok(());
+    }
+
+    // Follow the replies back to the first message of the response, which is
+    // what the generation is known by
+    let mut message_id = reaction.message_id;
+    for _ in 0..MAX_RESPONSE_MESSAGES {
+        if handler.user_generations.contains(user_id, message_id) {
+            tracing::info!(%message_id, "Cancelling generation from a reaction");
+            handler.generator.cancel(message_id);
+            return Ok(());
+        }
+        let message = reaction.channel_id.message(http, message_id).await?;
+        match message.message_reference.and_then(|r| r.message_id) {
+            Some(id) if message.author.bot => message_id = id,
+            _ => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+/// The reaction that cancels a generation when its requester adds it to the response
+const STOP_REACTION: &str = "⏹";
+
+/// Reacts to the message a generation is responding to, to show how it's going:
+/// an hourglass while it runs, then a check mark or a cross once it's done.
+struct StatusReaction<'a> {
+    http: &'a Http,
+    /// The message being reacted to, if reactions are enabled
+    message: Option<&'a Message>,
+}
+impl<'a> StatusReaction<'a> {
+    const RUNNING: char = '⏳';
+    const FINISHED: char = '✅';
+    const FAILED: char = '❌';
+
+    async fn start(http: &'a Http, message: &'a Message, enabled: bool) -> StatusReaction<'a> {
+        let message = enabled.then_some(message);
+        if let Some(message) = message {
+            // The reactions are only decoration, so failing to add them isn't fatal
+            if let Err(err) = message.react(http, Self::RUNNING).await {
+                tracing::warn!("Failed to react to message: {err}");
+            }
+        }
+        Self { http, message }
+    }
+
+    async fn finish(self, finished: bool) {
+        let Some(message) = self.message else { return; };
+        message
+            .channel_id
+            .delete_reaction(self.http, message.id, None, Self::RUNNING)
+            .await
+            .ok();
+        let reaction = if finished {
+            Self::FINISHED
+        } else {
+            Self::FAILED
+        };
+        if let Err(err) = message.react(self.http, reaction).await {
+            tracing::warn!("Failed to react to message: {err}");
+        }
+    }
+}
+
+/// Shows the prompt that would be given to the model in a code block, cut short to
+/// fit in a message if need be.
+fn dry_run_message(prompt: &str) -> String {
+    const MAX_LENGTH: usize = 1800;
+
+    // Keep the prompt from closing the code block early
+    let prompt = prompt.replace("```", "`\u{200b}``");
+    let (shown, cut) = match prompt.char_indices().nth(MAX_LENGTH) {
+        Some((index, _)) => (&prompt[..index], prompt[index..].chars().count()),
+        None => (prompt.as_str(), 0),
+    };
+
+    let mut message = format!("This is the prompt the model would be given:\n```\n{shown}\n```");
+    if cut > 0 {
+        message += &format!("*…and {cut} more characters.*");
+    }
+    message
+}
+
+/// The error the command's model failed to load with, if it did.
+fn model_load_error<'a>(handler: &'a Handler, command: &config::Command) -> Option<&'a String> {
+    handler
+        .config
+        .model_name_for(command)
+        .and_then(|model| handler.model_load_errors.get(model))
+}
+
+/// Downloads a text file attached as the prompt. Returns a message for the user
+/// if the file is too large or isn't text.
+async fn read_prompt_attachment(
+    attachment: &Attachment,
+    max_bytes: u64,
+) -> anyhow::Result<Result<String, String>> {
+    if attachment.size > max_bytes {
+        return Ok(Err(format!(
+            "`{}` is too large ({} bytes, max {max_bytes}).",
+            attachment.filename, attachment.size
+        )));
+    }
+
+    let bytes = attachment.download().await?;
+    Ok(match String::from_utf8(bytes) {
+        Ok(text) if !text.contains('\0') => Ok(text),
+        _ => Err(format!(
+            "`{}` isn't a text file; only UTF-8 text can be used as a prompt.",
+            attachment.filename
+        )),
+    })
+}
+
+/// Returns a message for the user if the prompt is longer than the command allows.
+fn check_prompt_chars(command: &config::Command, user_prompt: &str) -> Option<String> {
+    let max = command.max_prompt_chars?;
+    let chars = user_prompt.chars().count();
+    (chars > max).then(|| {
+        format!("Your prompt is too long for this command ({chars} characters, max {max}).")
+    })
+}
+
+/// Returns a message for the user if long prompts are rejected and the prompt
+/// doesn't leave enough room for the response.
+fn check_prompt_length(
+    handler: &Handler,
+    command: &config::Command,
+    prompts: &Prompts,
+) -> anyhow::Result<Option<String>> {
+    let inference = &handler.config.inference;
+    if !inference.reject_long_prompts {
+        return Ok(None);
+    }
+
+    let model = handler
+        .config
+        .model_name_for(command)
+        .context("no model configured for this command")?;
+    let model_config = &handler.config.models[model];
+    // Remote models are tokenized by their server, so there's no way to tell
+    if model_config.backend != config::Backend::Local {
+        return Ok(None);
+    }
+    let context_length = command
+        .context_token_length
+        .unwrap_or(model_config.context_token_length);
+
+    // The prompt is preceded by the beginning-of-sentence token
+    let tokens = handler.generator.tokenize(model, &prompts.processed)?.len() + 1;
+    let max = context_length.saturating_sub(inference.response_token_reserve);
+    Ok((tokens > max).then(|| format!("Your prompt is too long ({tokens} tokens, max {max}).")))
+}
+
+/// Runs a previous generation again with a new seed, writing the response over the old one.
+async fn regenerate(
+    cmp: &MessageComponentInteraction,
+    http: &Http,
+    handler: &Handler,
+    message_id: MessageId,
+) -> anyhow::Result<()> {
+    if handler.paused.load(Ordering::SeqCst) {
+        return util::create_ephemeral_component_response(cmp, http, PAUSED_MESSAGE).await;
+    }
+
+    // Held until the end of the generation, however it ends
+    let _in_flight = match handler.in_flight_generations.try_start(cmp.user.id) {
+        Ok(guard) => guard,
+        Err(message) => {
+            return util::create_ephemeral_component_response(cmp, http, &message).await
+        }
+    };
+
+    let previous = handler
+        .finished_generations
+        .get(message_id)
+        .filter(|g| g.conversation.is_none());
+    let Some(previous) = previous else {
+        return util::create_ephemeral_component_response(
+            cmp,
+            http,
+            "This response can no longer be regenerated.",
+        )
+        .await;
+    };
+    let config = &handler.config;
+    let command = config
+        .command(
+            previous.generation.guild_id,
+            &previous.generation.command_name,
+        )
+        .with_context(|| format!("no command named `{}`", previous.generation.command_name))?;
+    if let Err(message) = handler.cooldowns.try_start(cmp.user.id) {
+        return util::create_ephemeral_component_response(cmp, http, &message).await;
+    }
+
+    // The response is rewritten in place, so there's nothing else to respond with
+    cmp.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::DeferredUpdateMessage)
+    })
+    .await?;
+
+    let message = cmp.channel_id.message(http, message_id).await?;
+
+    let generation = Generation {
+        seed: rand::random(),
+        seed_chosen: false,
+        ..previous.generation.clone()
+    };
+    // Only removed as the response is rewritten, so that it can be regenerated again
+    // if anything before then fails. If it's already gone, someone else got there first.
+    if handler.finished_generations.take(message_id).is_none() {
+        return Ok(());
+    }
+    let outputter = match Outputter::for_existing_message(
+        http,
+        message,
+        cmp.user.id,
+        Prompts::new(command, &config.inference, &generation),
+        &config.inference,
+    )
+    .await
+    {
+        Ok(outputter) => outputter,
+        Err(err) => {
+            handler.finished_generations.insert(message_id, previous);
+            return Err(err);
+        }
+    };
+
+    // Reuse the first message of the previous response, and remove the rest
+    for id in previous.message_ids.iter().skip(1) {
+        cmp.channel_id.delete_message(http, id).await?;
+    }
+
+    run_generation(
+        http, handler, command, generation, outputter, &cmp.user, false,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Continues a previous response that was cut short, appending to its messages.
+async fn continue_generation(
+    cmp: &MessageComponentInteraction,
+    http: &Http,
+    handler: &Handler,
+    message_id: MessageId,
+) -> anyhow::Result<()> {
+    if handler.paused.load(Ordering::SeqCst) {
+        return util::create_ephemeral_component_response(cmp, http, PAUSED_MESSAGE).await;
+    }
+
+    // Held until the end of the generation, however it ends
+    let _in_flight = match handler.in_flight_generations.try_start(cmp.user.id) {
+        Ok(guard) => guard,
+        Err(message) => {
+            return util::create_ephemeral_component_response(cmp, http, &message).await
+        }
+    };
+
+    let Some(previous) = handler.finished_generations.get(message_id) else {
+        return util::create_ephemeral_component_response(
+            cmp,
+            http,
+            "This response can no longer be continued.",
+        )
+        .await;
+    };
+    let config = &handler.config;
+    let command = config
+        .command(
+            previous.generation.guild_id,
+            &previous.generation.command_name,
+        )
+        .with_context(|| format!("no command named `{}`", previous.generation.command_name))?;
+    if let Err(message) = handler.cooldowns.try_start(cmp.user.id) {
+        return util::create_ephemeral_component_response(cmp, http, &message).await;
+    }
+
+    // The response is extended in place, so there's nothing else to respond with
+    cmp.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::DeferredUpdateMessage)
+    })
+    .await?;
+
+    let mut messages = vec![];
+    for id in &previous.message_ids {
+        messages.push(cmp.channel_id.message(http, id).await?);
+    }
+
+    // Only removed as the response is extended, as with regenerating
+    if handler.finished_generations.take(message_id).is_none() {
+        return Ok(());
+    }
+    let outputter = match Outputter::for_continuation(
+        http,
+        messages,
+        cmp.user.id,
+        Prompts::new(command, &config.inference, &previous.generation),
+        &config.inference,
+        previous.response.clone(),
+        previous.replayed.clone(),
+    )
+    .await
+    {
+        Ok(outputter) => outputter,
+        Err(err) => {
+            handler.finished_generations.insert(message_id, previous);
+            return Err(err);
+        }
+    };
+
+    run_generation(
+        http,
+        handler,
+        command,
+        previous.generation,
+        outputter,
+        &cmp.user,
+        true,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Runs the generation, streaming its response into the outputter. If `continuation`
+/// is set, the response already in the outputter is continued. Returns whether the
+/// response was finished, rather than stopped by an error or cancellation.
+#[tracing::instrument(
+    name = "generation",
+    skip_all,
+    fields(
+        command = %generation.command_name,
+        user = %user.id,
+        continuation = continuation,
+        model = tracing::field::Empty,
+        message = tracing::field::Empty,
+    )
+)]
+async fn run_generation(
+    http: &Http,
+    handler: &Handler,
+    command: &config::Command,
+    generation: Generation,
+    mut outputter: Outputter<'_>,
+    user: &User,
+    continuation: bool,
+) -> anyhow::Result<bool> {
+    let config = &handler.config;
+    let inference = &config.inference;
+    let model = config
+        .model_name_for(command)
+        .context("no model configured for this command")?;
+    let first_message = outputter
+        .messages
+        .first()
+        .context("no message to write the response to")?;
+    let message_id = first_message.id;
+    let channel_id = first_message.channel_id;
+
+    let span = tracing::Span::current();
+    span.record("model", model);
+    span.record("message", tracing::field::display(message_id));
+    tracing::info!(seed = generation.seed, "Generation started");
+    if let Some(variant) = &generation.variant {
+        outputter.add_note(format!("variant: {variant}"));
+    }
+
+    handler.recent_generations.push(GenerationRecord {
+        time: std::time::Instant::now(),
+        user: user.tag(),
+        command: generation.command_name.clone(),
+        seed: generation.seed,
+        parameters: generation.parameters.clone(),
+        prompt: generation.user_prompt.clone(),
+    });
+
+    // Only the latest response in a conversation can be continued, as the session
+    // moves on with each turn
+    if command.conversational {
+        handler.finished_generations.forget_conversation(channel_id);
+    }
+
+    // Conversations continue from their session; anything else continues from
+    // the text so far
+    let prompt = if !continuation {
+        outputter.prompts.processed.clone()
+    } else if command.conversational {
+        String::new()
+    } else {
+        outputter.message.clone()
+    };
+
+    // Held until the end of the generation, so that `/cancel` can find it
+    let _user_generation = handler.user_generations.start(user.id, message_id);
+
+    let request = generation::Request {
+        model: model.to_string(),
+        prompt,
+        batch_size: generation.batch_size,
+        thread_count: command.thread_count(inference),
+        response_token_reserve: inference.response_token_reserve,
+        stop_sequences: generation.stop_sequences.clone(),
+        antiprompt: command.antiprompt.clone(),
+        max_tokens: generation.max_tokens,
+        token_biases: handler
+            .token_biases
+            .get(&(generation.guild_id, generation.command_name.clone()))
+            .or_else(|| {
+                handler
+                    .token_biases
+                    .get(&(None, generation.command_name.clone()))
+            })
+            .cloned()
+            .unwrap_or_default(),
+        discourage_eos: command.discourage_eos,
+        min_p: inference.min_p,
+        temperature: generation.temperature,
+        top_p: generation.top_p,
+        repeat_penalty: generation.repeat_penalty,
+        grammar: command.grammar.clone(),
+        context_size: command.context_token_length,
+        timeout: inference.max_generation_seconds.map(Duration::from_secs),
+        message_id,
+        seed: Some(generation.seed),
+        conversation: command.conversational.then_some(channel_id),
+        play_back_previous_tokens: command.conversational
+            && command.replay_conversation
+            && !continuation,
+        continuation,
+    };
+
+    // Conversations depend on their session, so only standalone responses are cached
+    let cache_key = (!command.conversational && !continuation)
+        .then(|| ResponseCacheKey::new(&generation, &request))
+        .flatten();
+    let stream = match cache_key
+        .as_ref()
+        .and_then(|k| handler.response_cache.get(k))
+    {
+        Some(cached) => {
+            tracing::info!("Replaying a cached response");
+            outputter.add_note("cached".to_string());
+            cached.replay(Duration::from_millis(
+                inference.cached_response_word_interval_ms,
+            ))
+        }
+        None => {
+            metrics::METRICS.request();
+            handler.generator.generate(request)?
+        }
+    };
+
+    let Some(truncated) =
+        output::stream_to_sink(stream, &mut outputter, inference.show_stats, &handler.stats)
+            .await?
+    else {
+        return Ok(false);
+    };
+    if let Err(err) = handler.stats.save() {
+        tracing::warn!("Failed to save stats: {err:?}");
+    }
+    // Keep the response as generated, before any trimming, so that it can be continued
+    let response = outputter.message.clone();
+    if let Some(key) = cache_key {
+        let seed = key.seed;
+        handler.response_cache.insert(
+            key,
+            CachedResponse {
+                seed,
+                text: response.clone(),
+                truncated,
+            },
+        );
+    }
+    outputter.finalize(truncated).await?;
+    tracing::info!(truncated, "Generation finished");
+
+    // Ephemeral and webhook responses can't be edited through the channel, so they can't
+    // be regenerated or continued
+    if command.ephemeral || command.webhook_username.is_some() {
+        return Ok(true);
+    }
+
+    // Conversational responses have already become part of the conversation, so they
+    // can't be regenerated, only continued
+    let regenerable = !command.conversational;
+    if regenerable || truncated {
+        if let Some(last) = outputter.messages.last_mut() {
+            add_finished_buttons(http, message_id, last, user.id, regenerable, truncated).await?;
+        }
+        handler.finished_generations.insert(
+            message_id,
+            FinishedGeneration {
+                generation,
+                message_ids: outputter.messages.iter().map(|m| m.id).collect(),
+                response,
+                replayed: std::mem::take(&mut outputter.replayed),
+                conversation: command.conversational.then_some(channel_id),
+            },
+        );
+    }
+
+    Ok(true)
+}
+
+/// Formats the most recent messages in the channel as a conversation, oldest first.
+async fn fetch_history(
+    http: &Http,
+    channel_id: ChannelId,
+    length: usize,
+) -> anyhow::Result<String> {
+    if length == 0 {
+        return Ok(String::new());
+    }
+
+    // Discord returns at most 100 messages at a time
+    let messages = channel_id
+        .messages(http, |r| r.limit(length.min(100) as u64))
+        .await?;
+
+    Ok(messages
+        .iter()
+        .rev()
+        // Skip the responses to commands, and messages without any text
+        .filter(|m| m.interaction.is_none() && !m.content.is_empty())
+        .map(|m| {
+            let speaker = if m.author.bot { "Bot" } else { "User" };
+            format!("{speaker}: {}", m.content)
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// Pauses or resumes generation for everyone.
+async fn admin(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    administration: &config::Administration,
+    paused: &AtomicBool,
+) -> anyhow::Result<()> {
+    if !administration.is_admin(cmd.user.id.0) {
+        return util::create_ephemeral_response(
+            cmd,
+            http,
+            "You don't have permission to use this command.",
+        )
+        .await;
+    }
+
+    let message = match cmd.data.options.first().map(|o| o.name.as_str()) {
+        Some(constant::value::PAUSE) => {
+            paused.store(true, Ordering::SeqCst);
+            "Generation is paused. Generations in progress will still finish."
+        }
+        Some(constant::value::RESUME) => {
+            paused.store(false, Ordering::SeqCst);
+            "Generation has resumed."
+        }
+        _ => anyhow::bail!("no subcommand specified"),
+    };
+    tracing::info!(user = %cmd.user.name, "{message}");
+
+    util::create_ephemeral_response(cmd, http, message).await
+}
+
+async fn recent_generations(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    administration: &config::Administration,
+    recent_generations: &RecentGenerations,
+) -> anyhow::Result<()> {
+    if !administration.is_admin(cmd.user.id.0) {
+        return util::create_ephemeral_response(
+            cmd,
+            http,
+            "You don't have permission to use this command.",
+        )
+        .await;
+    }
+
+    const PROMPT_PREVIEW_LENGTH: usize = 40;
+    // Leave some room under Discord's 2000 character limit for the code block
+    const MAX_LENGTH: usize = 1900;
+
+    let table = {
+        let records = recent_generations.records.lock().unwrap();
+
+        let mut table = String::new();
+        for record in records.iter().rev() {
+            let mut prompt: String = record
+                .prompt
+                .chars()
+                .take(PROMPT_PREVIEW_LENGTH)
+                .map(|c| if c == '\n' { ' ' } else { c })
+                .collect();
+            if record.prompt.chars().count() > PROMPT_PREVIEW_LENGTH {
+                prompt.push('…');
+            }
+
+            let line = format!(
+                "{}s ago | {} | /{} | seed: {} | {} | {prompt}\n",
+                record.time.elapsed().as_secs(),
+                record.user,
+                record.command,
+                record.seed,
+                record.parameters.join(" "),
+            );
+            if table.len() + line.len() > MAX_LENGTH {
+                break;
+            }
+            table += &line;
+        }
+
+        table
+    };
+
+    let message = if table.is_empty() {
+        "There have been no generations yet.".to_string()
+    } else {
+        format!("```\n{}```", table.replace("```", "'''"))
+    };
+    util::create_ephemeral_response(cmd, http, &message).await
+}
+
+/// Lists the enabled commands that can be used where `/help` was used: the global
+/// ones, and the guild's own commands if it has any.
+async fn help(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    config: &Configuration,
+) -> anyhow::Result<()> {
+    // Embeds can have at most 25 fields
+    const MAX_FIELDS: usize = 25;
+
+    let guild_id = cmd.guild_id.map(|g| g.0);
+    let guild_commands = guild_id
+        .and_then(|id| config.guild_commands().find(|(g, _)| *g == id))
+        .map(|(_, commands)| commands.keys());
+    let names: std::collections::BTreeSet<&str> = config
+        .commands
+        .keys()
+        .chain(guild_commands.into_iter().flatten())
+        .map(|name| name.as_str())
+        .collect();
+    let commands: Vec<_> = names
+        .into_iter()
+        .filter_map(|name| Some((name, config.command(guild_id, name)?)))
+        .filter(|(_, command)| command.enabled)
+        .collect();
+
+    cmd.create_interaction_response(http, |response| {
+        response
+            .kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|message| {
+                message.ephemeral(true).embed(|e| {
+                    e.title("Commands");
+                    if commands.is_empty() {
+                        e.description("There are no commands available here.");
+                    } else if commands.len() > MAX_FIELDS {
+                        e.description(format!(
+                            "Only the first {MAX_FIELDS} of {} commands are shown.",
+                            commands.len()
+                        ));
+                    }
+                    for &(name, command) in commands.iter().take(MAX_FIELDS) {
+                        let mut parameters = constant::value::PARAMETERS.to_vec();
+                        parameters.extend(command.options.iter().map(|o| o.name.as_str()));
+                        if !command.presets.is_empty() {
+                            parameters.push(constant::value::PRESET);
+                        }
+                        let names = std::iter::once(name)
+                            .chain(command.aliases.iter().map(|a| a.as_str()))
+                            .map(|n| format!("/{n}"))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        e.field(
+                            names,
+                            format!(
+                                "{}\nParameters: {}",
+                                command.description,
+                                parameters
+                                    .iter()
+                                    .map(|p| format!("`{p}`"))
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            ),
+                            false,
+                        );
+                    }
+                    e
+                })
+            })
+    })
+    .await?;
+
+    Ok(())
+}
+
+async fn whoami(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    config: &Configuration,
+) -> anyhow::Result<()> {
+    // Free-form, so resolved as the command would be when used here
+    let command = match util::get_value(&cmd.data.options, constant::value::COMMAND)
+        .and_then(util::value_to_string)
+    {
+        Some(name) => match config.resolve_command(cmd.guild_id.map(|g| g.0), &name) {
+            Some((name, command)) => Some((name.to_string(), command)),
+            None => {
+                return util::create_ephemeral_response(
+                    cmd,
+                    http,
+                    &format!("There is no command named `{name}` here."),
+                )
+                .await
+            }
+        },
+        None => None,
+    };
+    let role_ids: Vec<u64> = cmd
+        .member
+        .iter()
+        .flat_map(|m| &m.roles)
+        .map(|r| r.0)
+        .collect();
+
+    let inference = &config.inference;
+    let thread_count = match &command {
+        Some((_, command)) => command.thread_count(inference),
+        None => config::Command::default_thread_count(inference),
+    };
+
+    cmd.create_interaction_response(http, |response| {
+        response
+            .kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|message| {
+                message.ephemeral(true).embed(|e| {
+                    e.title("Effective settings");
+
+                    // Show the model the command uses, or all of them if no command was specified
+                    let models = config.models.iter().filter(|(name, _)| match &command {
+                        Some((_, command)) => config.model_name_for(command) == Some(name.as_str()),
+                        None => true,
+                    });
+                    for (name, model) in models {
+                        e.field(
+                            format!("Model: {name}"),
+                            format!(
+                                "Path: `{}`\n\
+                                 Architecture: {}\n\
+                                 Context length: {} tokens\n\
+                                 Instances: {}",
+                                model.path.display(),
+                                model.architecture,
+                                model.context_token_length,
+                                model.instances,
+                            ),
+                            false,
+                        );
+                    }
+
+                    e.field(
+                        "Inference",
+                        format!(
+                            "Threads: {thread_count}\n\
+                             Batch size: {}\n\
+                             Max tokens: {}\n\
+                             Reserved response tokens: {}\n\
+                             Prompt display: {:?}\n\
+                             Trim incomplete sentences: {}",
+                            inference.batch_size,
+                            inference.max_tokens,
+                            inference.response_token_reserve,
+                            inference.prompt_display_style,
+                            inference.trim_incomplete_sentence,
+                        ),
+                        false,
+                    );
+
+                    e.field(
+                        "Limits",
+                        format!(
+                            "Generations per user: {}\n\
+                             Generations in total: {}\n\
+                             Cooldown: {} seconds",
+                            display_limit(inference.max_concurrent_per_user),
+                            display_limit(inference.max_queue_size),
+                            inference.user_cooldown_seconds,
+                        ),
+                        false,
+                    );
+
+                    if let Some((name, command)) = &command {
+                        let defaults = &command.defaults;
+                        e.field(
+                            format!("/{name}"),
+                            format!(
+                                "{}\n\
+                                 Enabled: {}\n\
+                                 Ephemeral: {}\n\
+                                 Max tokens: {}\n\
+                                 Seed: {}\n\
+                                 Temperature: {}\n\
+                                 Top-p: {}\n\
+                                 Repeat penalty: {}\n\
+                                 Max prompt characters: {}\n\
+                                 Stop on double newline: {}\n\
+                                 Stop sequences: {:?}\n\
+                                 Allowed: {}",
+                                command.description,
+                                command.enabled,
+                                command.ephemeral,
+                                command.default_max_tokens(inference),
+                                display_default(defaults.seed, "random"),
+                                display_default(defaults.temperature, "llm's default"),
+                                display_default(defaults.top_p, "llm's default"),
+                                display_default(defaults.repeat_penalty, "llm's default"),
+                                display_limit(command.max_prompt_chars),
+                                command.stop_on_double_newline,
+                                command.stop_sequences,
+                                display_allowed(command),
+                            ),
+                            false,
+                        );
+                    }
+
+                    let mut you = format!(
+                        "Administrator: {}",
+                        config.administration.is_admin(cmd.user.id.0)
+                    );
+                    if let Some((name, command)) = &command {
+                        you += &format!(
+                            "\nCan use /{name}: {}",
+                            command.is_allowed(cmd.user.id.0, &role_ids)
+                        );
+                    }
+                    e.field("You", you, false)
+                })
+            })
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Formats an optional limit for `/whoami`.
+fn display_limit(limit: Option<usize>) -> String {
+    limit.map_or("none".to_string(), |limit| limit.to_string())
+}
+
+/// Formats a command default for `/whoami`, or what's used when it isn't set.
+fn display_default(value: Option<impl std::fmt::Display>, unset: &str) -> String {
+    value.map_or(unset.to_string(), |value| value.to_string())
+}
+
+/// Formats the roles and users a command is restricted to as mentions, which
+/// don't notify anyone in an embed.
+fn display_allowed(command: &config::Command) -> String {
+    if command.allowed_roles.is_empty() && command.allowed_users.is_empty() {
+        return "everyone".to_string();
+    }
+    let roles = command.allowed_roles.iter().map(|id| format!("<@&{id}>"));
+    let users = command.allowed_users.iter().map(|id| format!("<@{id}>"));
+    roles.chain(users).collect::<Vec<_>>().join(", ")
+}
+
+async fn tokenize(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    config: &Configuration,
+    generator: &Generator,
+) -> anyhow::Result<()> {
+    use constant::value as v;
+
+    // Leave some room under Discord's 2000 character limit for the summary
+    const MAX_TOKENS_LENGTH: usize = 1800;
+
+    let options = &cmd.data.options;
+    let text = util::get_value(options, v::TEXT)
+        .and_then(util::value_to_string)
+        .context("no text specified")?;
+    let model = match util::get_value(options, v::MODEL).and_then(util::value_to_string) {
+        Some(model) => model,
+        None if config.models.len() == 1 => config.models.keys().next().unwrap().clone(),
+        None => anyhow::bail!("no model specified"),
+    };
+    let show_tokens = util::get_value(options, v::SHOW_TOKENS)
+        .and_then(util::value_to_bool)
+        .unwrap_or(false);
+
+    let tokens = generator.tokenize(&model, &text)?;
+    let mut message = format!("**{}** tokens with `{model}`", tokens.len());
+    if let Some(context_length) = config.models.get(&model).map(|m| m.context_token_length) {
+        message += &format!(", out of a context of {context_length}");
+    }
+
+    if show_tokens {
+        let mut pieces = String::new();
+        for (bytes, _) in &tokens {
+            let piece = format!("{}|", String::from_utf8_lossy(bytes));
+            if pieces.len() + piece.len() > MAX_TOKENS_LENGTH {
+                pieces.push('…');
+                break;
+            }
+            pieces += &piece;
+        }
+        message += &format!("\n```\n|{}\n```", pieces.replace("```", "'''"));
+    }
+
+    util::create_ephemeral_response(cmd, http, &message).await
+}
+
+async fn models(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    config: &Configuration,
+    model_load_errors: &HashMap<String, String>,
+) -> anyhow::Result<()> {
+    let mut models: Vec<_> = config.models.iter().collect();
+    models.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    cmd.create_interaction_response(http, |response| {
+        response
+            .kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|message| {
+                message.ephemeral(true).embed(|e| {
+                    e.title("Models");
+                    for &(name, model) in &models {
+                        let status = match model_load_errors.get(name) {
+                            Some(err) => format!("Failed to load: {err}"),
+                            None if model.is_fallback => "Loaded its fallback".to_string(),
+                            None => "Loaded".to_string(),
+                        };
+                        e.field(
+                            name,
+                            format!(
+                                "Path: `{}`\n\
+                                 Architecture: {}\n\
+                                 Context length: {} tokens\n\
+                                 Memory-mapped: {}\n\
+                                 Instances: {}\n\
+                                 Status: {status}",
+                                model.path.display(),
+                                model.architecture,
+                                model.context_token_length,
+                                model.prefer_mmap,
+                                model.instances,
+                            ),
+                            false,
+                        );
+                    }
+                    e
+                })
+            })
+    })
+    .await?;
+
+    Ok(())
+}
+
+async fn stats(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    stats: &Stats,
+) -> anyhow::Result<()> {
+    let snapshot = stats.snapshot();
+
+    cmd.create_interaction_response(http, |response| {
+        response
+            .kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|message| {
+                message.embed(|e| {
+                    e.title("Stats")
+                        .field("Uptime", util::format_duration(snapshot.uptime), true)
+                        .field("Generations", snapshot.generations, true)
+                        .field("Tokens generated", snapshot.tokens, true)
+                        .field(
+                            "Average speed",
+                            format!("{:.1} tokens/s", snapshot.tokens_per_second()),
+                            true,
+                        )
+                })
+            })
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// The most recent responses to seeded requests, so that identical requests can be
+/// answered without running the model again. The least recently used are dropped
+/// first.
+struct ResponseCache {
+    capacity: usize,
+    entries: Mutex<VecDeque<(ResponseCacheKey, CachedResponse)>>,
+}
+/// Everything that decides the response to a request.
+#[derive(PartialEq)]
+struct ResponseCacheKey {
+    /// The command decides the rest of the request, such as its token biases
+    command_name: String,
+    guild_id: Option<u64>,
+    model: String,
+    prompt: String,
+    seed: u64,
+    max_tokens: usize,
+    stop_sequences: Vec<String>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    repeat_penalty: Option<f32>,
+}
+#[derive(Clone)]
+struct CachedResponse {
+    seed: u64,
+    /// The text of the response, including the prompt, as generated
+    text: String,
+    truncated: bool,
+}
+impl ResponseCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn get(&self, key: &ResponseCacheKey) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let index = entries.iter().position(|(k, _)| k == key)?;
+        let entry = entries.remove(index)?;
+        let response = entry.1.clone();
+        entries.push_back(entry);
+        Some(response)
+    }
+
+    fn insert(&self, key: ResponseCacheKey, response: CachedResponse) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(k, _)| *k != key);
+        while entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back((key, response));
+    }
+}
+impl ResponseCacheKey {
+    /// The key for the request, unless its seed was picked at random, in which case
+    /// no one will ask for the same response again.
+    fn new(generation: &Generation, request: &generation::Request) -> Option<Self> {
+        if !generation.seed_chosen {
+            return None;
+        }
+
+        Some(Self {
+            command_name: generation.command_name.clone(),
+            guild_id: generation.guild_id,
+            model: request.model.clone(),
+            prompt: request.prompt.clone(),
+            seed: request.seed?,
+            max_tokens: request.max_tokens,
+            stop_sequences: request.stop_sequences.clone(),
+            temperature: request.temperature,
+            top_p: request.top_p,
+            repeat_penalty: request.repeat_penalty,
+        })
+    }
+}
+impl CachedResponse {
+    /// Streams the response like the generation thread would, a word at a time with
+    /// `interval` between each, or all at once if `interval` is zero.
+    fn replay(&self, interval: Duration) -> flume::r#async::RecvStream<'static, generation::Token> {
+        use generation::Token;
+
+        let (token_tx, token_rx) = flume::unbounded();
+        token_tx.send(Token::Seed(self.seed)).ok();
+        let words: Vec<String> = if interval.is_zero() {
+            vec![self.text.clone()]
+        } else {
+            self.text.split_inclusive(' ').map(String::from).collect()
+        };
+        let truncated = self.truncated;
+        tokio::spawn(async move {
+            for word in words {
+                tokio::time::sleep(interval).await;
+                if token_tx.send(Token::Token(word)).is_err() {
+                    return;
+                }
+            }
+            if truncated {
+                token_tx.send(Token::Truncated).ok();
+            }
+        });
+        token_rx.into_stream()
+    }
+}
+
+/// A bounded log of the most recent generations, kept for auditing.
+struct RecentGenerations {
+    capacity: usize,
+    records: Mutex<VecDeque<GenerationRecord>>,
+}
+impl RecentGenerations {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn push(&self, record: GenerationRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut records = self.records.lock().unwrap();
+        while records.len() >= self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+}
+
+/// The generations that are queued or in progress, used to stop any one
+/// user (or everyone together) from flooding the queue.
+struct InFlightGenerations {
+    max_per_user: Option<usize>,
+    max_total: Option<usize>,
+    counts: Mutex<HashMap<UserId, usize>>,
+    /// Set when the bot is shutting down, after which no new generations can start
+    closed: AtomicBool,
+}
+impl InFlightGenerations {
+    fn new(max_per_user: Option<usize>, max_total: Option<usize>) -> Self {
+        Self {
+            max_per_user,
+            max_total,
+            counts: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.counts.lock().unwrap().is_empty()
+    }
+
+    /// Reserves a generation for the user, which is released when the returned
+    /// guard is dropped. If the limits have been reached, returns the message
+    /// to show the user instead.
+    fn try_start(&self, user: UserId) -> Result<InFlightGeneration<'_>, String> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err("The bot is shutting down. Please try again later.".to_string());
+        }
+
+        let mut counts = self.counts.lock().unwrap();
+
+        let user_count = counts.get(&user).copied().unwrap_or_default();
+        if let Some(max) = self.max_per_user {
+            if user_count >= max {
+                return Err(format!(
+                    "You already have {user_count} generations in progress. Please wait for them to finish."
+                ));
+            }
+        }
+
+        let total: usize = counts.values().sum();
+        if let Some(max) = self.max_total {
+            if total >= max {
+                return Err(format!(
+                    "There are already {total} generations in progress. Please try again later."
+                ));
+            }
+        }
+
+        counts.insert(user, user_count + 1);
+        Ok(InFlightGeneration {
+            in_flight: self,
+            user,
+        })
+    }
+}
+
+/// When each user last started a generation, to make them wait between generations.
+struct Cooldowns {
+    cooldown: Duration,
+    last_started: Mutex<HashMap<UserId, std::time::Instant>>,
+}
+impl Cooldowns {
+    fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            last_started: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records that the user is starting a generation, or returns the message to
+    /// show them if they're still cooling down from their last one.
+    fn try_start(&self, user: UserId) -> Result<(), String> {
+        if self.cooldown.is_zero() {
+            return Ok(());
+        }
+
+        let mut last_started = self.last_started.lock().unwrap();
+        let now = std::time::Instant::now();
+        // Forget the users who have cooled down, so that this doesn't grow forever
+        last_started.retain(|_, started| now.duration_since(*started) < self.cooldown);
+
+        if let Some(started) = last_started.get(&user) {
+            let remaining = self.cooldown - now.duration_since(*started);
+            return Err(format!(
+                "Please wait {} more seconds before starting another generation.",
+                remaining.as_secs_f64().ceil()
+            ));
+        }
+
+        last_started.insert(user, now);
+        Ok(())
+    }
+}
+
+struct InFlightGeneration<'a> {
+    in_flight: &'a InFlightGenerations,
+    user: UserId,
+}
+impl Drop for InFlightGeneration<'_> {
+    fn drop(&mut self) {
+        let mut counts = self.in_flight.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.user) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.user);
+            }
+        }
+    }
+}
+
+/// The generations in progress for each user, in the order they started, so that
+/// users can cancel them without the button.
+#[derive(Default)]
+struct UserGenerations {
+    generations: Mutex<HashMap<UserId, Vec<MessageId>>>,
+}
+impl UserGenerations {
+    fn start(&self, user: UserId, message_id: MessageId) -> UserGeneration<'_> {
+        self.generations
+            .lock()
+            .unwrap()
+            .entry(user)
+            .or_default()
+            .push(message_id);
+        UserGeneration {
+            user_generations: self,
+            user,
+            message_id,
+        }
+    }
+
+    /// Whether the user started the generation whose response begins with the message,
+    /// and it's still in progress.
+    fn contains(&self, user: UserId, message_id: MessageId) -> bool {
+        self.generations
+            .lock()
+            .unwrap()
+            .get(&user)
+            .map_or(false, |ids| ids.contains(&message_id))
+    }
+
+    /// The generation the user started most recently that's still in progress.
+    fn latest(&self, user: UserId) -> Option<MessageId> {
+        self.generations
+            .lock()
+            .unwrap()
+            .get(&user)
+            .and_then(|ids| ids.last().copied())
+    }
+}
+
+struct UserGeneration<'a> {
+    user_generations: &'a UserGenerations,
+    user: UserId,
+    message_id: MessageId,
+}
+impl Drop for UserGeneration<'_> {
+    fn drop(&mut self) {
+        let mut generations = self.user_generations.generations.lock().unwrap();
+        if let Some(ids) = generations.get_mut(&self.user) {
+            ids.retain(|id| *id != self.message_id);
+            if ids.is_empty() {
+                generations.remove(&self.user);
+            }
+        }
+    }
+}
+
+/// The parameters of a generation, independent of the interaction that asked
+/// for it, so that it can be run again.
+#[derive(Clone)]
+struct Generation {
+    command_name: String,
+    /// The guild the command was used in, which decides which command the name refers to
+    guild_id: Option<u64>,
+    user_prompt: String,
+    /// The text of the preset the user picked, if any
+    preset: Option<String>,
+    /// The values of the command's extra options that the user filled in, by name
+    options: HashMap<String, String>,
+    /// The recent messages in the channel, for commands that include them in the prompt
+    history: String,
+    /// The language the user asked for the response to be in, if any
+    language: Option<String>,
+    /// The name of the prompt variant picked for the generation, if the command has any
+    variant: Option<String>,
+    /// The seed to sample with, which also picks the prompt variant
+    seed: u64,
+    /// Whether the user or the command chose the seed, rather than it being random
+    seed_chosen: bool,
+    max_tokens: usize,
+    batch_size: usize,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    repeat_penalty: Option<f32>,
+    stop_sequences: Vec<String>,
+    /// The options the user specified other than the prompt and seed, for auditing
+    parameters: Vec<String>,
+}
+impl Generation {
+    /// A generation of a global command with its default parameters, for when there
+    /// are no options to read them from.
+    fn with_defaults(
+        command_name: &str,
+        command: &config::Command,
+        inference: &config::Inference,
+        user_prompt: String,
+        history: String,
+    ) -> Self {
+        let mut stop_sequences = command.stop_sequences.clone();
+        if command.stop_on_double_newline {
+            stop_sequences.push("\n\n".to_string());
+        }
+        let seed = command.defaults.seed.unwrap_or_else(rand::random);
+
+        Self {
+            command_name: command_name.to_string(),
+            guild_id: None,
+            user_prompt,
+            preset: None,
+            options: HashMap::new(),
+            history,
+            language: None,
+            variant: command.pick_variant(seed).map(|v| v.name.clone()),
+            seed,
+            seed_chosen: command.defaults.seed.is_some(),
+            max_tokens: command.default_max_tokens(inference),
+            batch_size: inference.batch_size,
+            temperature: clamped_default(command.defaults.temperature, TEMPERATURE_RANGE),
+            top_p: clamped_default(command.defaults.top_p, TOP_P_RANGE),
+            repeat_penalty: clamped_default(command.defaults.repeat_penalty, REPEAT_PENALTY_RANGE),
+            stop_sequences,
+            parameters: vec![],
+        }
+    }
+}
+
+/// The most recent finished generations, keyed by the first message of their
+/// response, so that they can be regenerated or continued.
+#[derive(Default)]
+struct FinishedGenerations {
+    generations: Mutex<VecDeque<(MessageId, FinishedGeneration)>>,
+}
+#[derive(Clone)]
+struct FinishedGeneration {
+    generation: Generation,
+    /// The messages the response was written to
+    message_ids: Vec<MessageId>,
+    /// The text of the response, including the prompt, as generated
+    response: String,
+    /// The conversation that was played back above the response, if any
+    replayed: String,
+    /// The channel of the conversation the response is part of, if any
+    conversation: Option<ChannelId>,
+}
+impl FinishedGenerations {
+    /// Only the most recent responses can be regenerated or continued, to bound memory use.
+    const CAPACITY: usize = 100;
+
+    fn insert(&self, message_id: MessageId, generation: FinishedGeneration) {
+        let mut generations = self.generations.lock().unwrap();
+        while generations.len() >= Self::CAPACITY {
+            generations.pop_front();
+        }
+        generations.push_back((message_id, generation));
+    }
+
+    fn get(&self, message_id: MessageId) -> Option<FinishedGeneration> {
+        let generations = self.generations.lock().unwrap();
+        generations
+            .iter()
+            .find(|(id, _)| *id == message_id)
+            .map(|(_, g)| g.clone())
+    }
+
+    fn take(&self, message_id: MessageId) -> Option<FinishedGeneration> {
+        let mut generations = self.generations.lock().unwrap();
+        let index = generations.iter().position(|(id, _)| *id == message_id)?;
+        generations.remove(index).map(|(_, g)| g)
+    }
+
+    /// Forgets the responses in the conversation in the channel, as they can no
+    /// longer be continued once the conversation has moved on.
+    fn forget_conversation(&self, channel_id: ChannelId) {
+        self.generations
+            .lock()
+            .unwrap()
+            .retain(|(_, g)| g.conversation != Some(channel_id));
+    }
+}
+
+struct GenerationRecord {
+    time: std::time::Instant,
+    user: String,
+    command: String,
+    seed: u64,
+    parameters: Vec<String>,
+    prompt: String,
+}
+
+struct Prompts {
+    show_prompt_template: bool,
+    display_style: config::PromptDisplayStyle,
+
+    /// The prompt given to the model
+    processed: String,
+    /// The prompt as shown with the template, without the global prefix and suffix
+    shown: String,
+    user: String,
+    /// The command's template with everything but `{{PROMPT}}` filled in
+    template: String,
+    global_prefix: String,
+    global_suffix: String,
+}
+impl Prompts {
+    fn new(
+        command: &config::Command,
+        inference: &config::Inference,
+        generation: &Generation,
+    ) -> Self {
+        let prompt = command.template(generation.variant.as_deref());
+        let template = match &generation.preset {
+            Some(preset) if !prompt.contains("{{PRESET}}") => format!("{preset}\n\n{prompt}"),
+            preset => prompt.replace("{{PRESET}}", preset.as_deref().unwrap_or_default()),
+        };
+        let template = match &command.system_prompt {
+            Some(system_prompt) if !template.contains("{{SYSTEM}}") => {
+                format!("{system_prompt}\n\n{template}")
+            }
+            system_prompt => {
+                template.replace("{{SYSTEM}}", system_prompt.as_deref().unwrap_or_default())
+            }
+        };
+        let template = command.options.iter().fold(template, |template, option| {
+            let value = generation.options.get(&option.name);
+            template.replace(&option.placeholder(), value.map_or("", |v| v.as_str()))
+        });
+        let template = template.replace("{{HISTORY}}", &generation.history);
+
+        // The language instruction goes with the global prefix, so that it's never shown
+        let mut global_prefix = inference.global_prompt_prefix.clone().unwrap_or_default();
+        if let Some(language) = &generation.language {
+            global_prefix.push_str(&format!("Respond in {language}.\n\n"));
+        }
+        let global_suffix = inference.global_prompt_suffix.clone().unwrap_or_default();
+        let shown = template.replace("{{PROMPT}}", &generation.user_prompt);
+
+        Self {
+            show_prompt_template: inference.show_prompt_template,
+            display_style: inference.prompt_display_style,
+            processed: format!("{global_prefix}{shown}{global_suffix}"),
+            shown,
+            user: generation.user_prompt.clone(),
+            template,
+            global_prefix,
+            global_suffix,
+        }
+    }
+
+    /// Returns the message as it should be displayed, along with the prompt it
+    /// should be displayed with.
+    fn displayed_message_and_prompt(&self, message: &str) -> (String, &String) {
+        let message = self.strip_global_prompt(message);
+        if !self.show_prompt_template {
+            (self.decouple_prompt_from_message(&message), &self.user)
+        } else {
+            (message, &self.shown)
+        }
+    }
+
+    /// Removes the global prompt prefix and suffix from the message, as they're
+    /// never shown.
+    fn strip_global_prompt(&self, message: &str) -> String {
+        let Some(message) = message.strip_prefix(&self.global_prefix) else {
+            // The model is still reading the prefix
+            return if self.global_prefix.starts_with(message) {
+                String::new()
+            } else {
+                message.to_string()
+            };
+        };
+        let Some(rest) = message.strip_prefix(&self.shown) else { return message.to_string(); };
+        match rest.strip_prefix(&self.global_suffix) {
+            Some(response) => format!("{}{response}", self.shown),
+            // The model is still reading the suffix
+            None if self.global_suffix.starts_with(rest) => self.shown.clone(),
+            None => message.to_string(),
+        }
+    }
+
+    fn make_markdown_message(&self, message: &str) -> String {
+        let (message, display_prompt) = self.displayed_message_and_prompt(message);
+
+        match self.display_style {
+            config::PromptDisplayStyle::Strikethrough => match message.strip_prefix(display_prompt)
+            {
+                Some(msg) => format!("**{display_prompt}**{msg}"),
+                None => match display_prompt.strip_prefix(&message) {
+                    Some(ungenerated) => {
+                        if message.is_empty() {
+                            format!("~~{ungenerated}~~")
+                        } else {
+                            format!("**{message}**~~{ungenerated}~~")
+                        }
+                    }
+                    None => message.to_string(),
+                },
+            },
+            style @ (config::PromptDisplayStyle::Blockquote
+            | config::PromptDisplayStyle::Hidden) => {
+                let response = match message.strip_prefix(display_prompt) {
+                    Some(response) => response.trim_start(),
+                    // The model is still reading the prompt
+                    None if display_prompt.starts_with(&message) => "",
+                    None => return message.to_string(),
+                };
+
+                if style == config::PromptDisplayStyle::Hidden {
+                    // Discord won't accept an empty message
+                    return if response.is_empty() {
+                        "…".to_string()
+                    } else {
+                        response.to_string()
+                    };
+                }
+
+                let quote = display_prompt
+                    .lines()
+                    .map(|l| format!("> {l}"))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                format!("{quote}\n{response}")
+            }
+        }
+    }
+
+    /// Makes the description of a response embed. The prompt goes in the title, so
+    /// this is the response, or the parts of the prompt the model has yet to read
+    /// struck through.
+    fn make_embed_description(&self, message: &str) -> String {
+        let (message, display_prompt) = self.displayed_message_and_prompt(message);
+
+        match message.strip_prefix(display_prompt) {
+            // Discord won't accept an empty description
+            Some(response) if response.trim().is_empty() => "…".to_string(),
+            Some(response) => response.trim_start().to_string(),
+            None => match display_prompt.strip_prefix(&message) {
+                Some(ungenerated) => format!("~~{ungenerated}~~"),
+                None => message,
+            },
+        }
+    }
+
+    /// Makes the text of the response: its embed description or markdown message,
+    /// below the conversation played back before it, if any. The replayed text is
+    /// kept apart from the message so that the prompt can still be found in it.
+    fn make_response(&self, replayed: &str, message: &str, use_embeds: bool) -> String {
+        let response = if use_embeds {
+            self.make_embed_description(message)
+        } else {
+            self.make_markdown_message(message)
+        };
+        if replayed.is_empty() {
+            response
+        } else {
+            format!("{}\n{response}", replayed.trim_end())
+        }
+    }
+
+    /// Makes the embed for the chunk at `index`. Only the first embed has the prompt as its title.
+    fn make_embed(&self, index: usize, chunk: &str) -> CreateEmbed {
+        // Embed titles can be at most 256 characters long
+        const MAX_TITLE_LENGTH: usize = 256;
+
+        let mut embed = CreateEmbed::default();
+        if index == 0 {
+            let prompt = if self.show_prompt_template {
+                &self.shown
+            } else {
+                &self.user
+            };
+            let mut title: String = prompt.chars().take(MAX_TITLE_LENGTH - 1).collect();
+            if title.len() < prompt.len() {
+                title.push('…');
+            }
+            embed.title(title);
+        }
+        embed.description(chunk);
+        embed
+    }
+
+    fn decouple_prompt_from_message(&self, output: &str) -> String {
+        let (prefix, suffix) = self.template.split_once("{{PROMPT}}").unwrap_or_default();
+
+        let prompt = &self.user;
+
+        let Some(message) = output.strip_prefix(prefix) else { return String::new(); };
+        let Some(response) = message.strip_prefix(prompt) else { return message.to_string(); };
+        let Some(response) = response.strip_prefix(suffix) else { return prompt.to_string(); };
+
+        let newline = if suffix.ends_with('\n') { "\n" } else { "" };
+
+        format!("{prompt}{newline}{response}")
+    }
+}
+
+/// Where an `Outputter` writes the response.
+enum Destination<'a> {
+    /// Messages in the channel, posted and edited by the bot
+    Channel,
+    /// The ephemeral interaction response, which can only be edited through
+    /// the interaction and can't be split across several messages
+    Ephemeral(&'a ApplicationCommandInteraction),
+    /// Messages posted through a channel webhook under a custom persona
+    Webhook {
+        webhook: Webhook,
+        username: String,
+        avatar_url: Option<String>,
+    },
+}
+
+/// Why a generation ended early, which decides how that's shown.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    /// The generation failed
+    Error,
+    /// The user stopped the generation
+    Cancellation,
+}
+impl Severity {
+    fn colour(self) -> Colour {
+        match self {
+            Severity::Error => Colour::RED,
+            Severity::Cancellation => Colour::LIGHT_GREY,
+        }
+    }
+
+    /// Formats the message for a plain text reply.
+    fn format(self, message: &str) -> String {
+        match self {
+            Severity::Error => format!("**Error:** {message}"),
+            Severity::Cancellation => format!("*{message}*"),
+        }
+    }
+
+    fn make_embed(self, message: &str) -> CreateEmbed {
+        let mut embed = CreateEmbed::default();
+        embed.colour(self.colour()).description(message);
+        if self == Severity::Error {
+            embed.title("Error");
+        }
+        embed
+    }
+}
+
+struct Outputter<'a> {
+    http: &'a Http,
+
+    user_id: UserId,
+    messages: Vec<Message>,
+    chunks: Vec<String>,
+    /// The chunks as they were last sent to each message, so that unchanged
+    /// messages aren't edited
+    synced_chunks: Vec<String>,
+
+    message: String,
+    /// The conversation so far, if it's being played back above the response
+    replayed: String,
+    prompts: Prompts,
+    /// The status of a generation that hasn't started responding yet, shown until the
+    /// first token arrives or the model has finished reading the prompt
+    status: Option<String>,
+    /// Short notes about the generation, shown after the response
+    notes: Vec<String>,
+
+    in_terminal_state: bool,
+    trim_incomplete_sentence: bool,
+    strike_on_cancel: bool,
+    /// Whether or not the response gets a cancel button
+    allow_cancel: bool,
+    use_embeds: bool,
+    message_chunk_size: usize,
+    destination: Destination<'a>,
+
+    last_update: std::time::Instant,
+    last_update_duration: std::time::Duration,
+}
+impl<'a> Outputter<'a> {
+    /// Embed descriptions can be up to 4096 characters long; stay a little under that.
+    const EMBED_CHUNK_SIZE: usize = 4000;
+    /// The longest the interval between updates can grow to after being rate limited
+    const MAX_UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+    /// How many times to try the updates that can't be skipped when rate limited
+    const RATE_LIMITED_ATTEMPTS: usize = 3;
+    /// How much a message has to change by before it's worth updating mid-generation
+    const MIN_UPDATE_DELTA: usize = 8;
+    /// How long the response can get before the update interval doubles, and
+    /// then doubles again for each time it grows by as much
+    const INTERVAL_DOUBLING_LENGTH: usize = 2000;
+    /// The most times the update interval can double for a long response
+    const MAX_INTERVAL_DOUBLINGS: u32 = 3;
+
+    /// Writes the response into the command's deferred interaction response.
+    async fn new(
+        http: &'a Http,
+        cmd: &'a ApplicationCommandInteraction,
+        prompts: Prompts,
+        inference: &config::Inference,
+        command: &config::Command,
+    ) -> anyhow::Result<Outputter<'a>> {
+        if let Some(username) = &command.webhook_username {
+            cmd.edit_original_interaction_response(http, |m| {
+                m.content(format!("Responding as **{username}**…"))
+            })
+            .await?;
+            return Self::for_webhook(
+                http,
+                cmd.channel_id,
+                cmd.user.id,
+                prompts,
+                inference,
+                command,
+                username,
+            )
+            .await;
+        }
 
-            let markdown = self.prompts.make_markdown_message(&self.message);
-            for word in markdown.split(' ') {
-                if let Some(last) = chunks.last_mut() {
-                    if last.len() > Self::MESSAGE_CHUNK_SIZE {
-                        chunks.push(word.to_string());
-                    } else {
-                        last.push(' ');
-                        last.push_str(word);
-                    }
+        let ephemeral = command.ephemeral;
+        let use_embeds = inference.use_embeds;
+        let starting_message = cmd
+            .edit_original_interaction_response(http, |message| {
+                if use_embeds {
+                    message.set_embed(prompts.make_embed(0, &prompts.make_embed_description("")));
+                } else {
+                    message.content(prompts.make_markdown_message(""));
+                }
+                message.allowed_mentions(|m| m.empty_roles().empty_users().empty_parse())
+            })
+            .await?;
+
+        Ok(Self::with_message(
+            http,
+            starting_message,
+            cmd.user.id,
+            prompts,
+            inference,
+            if ephemeral {
+                Destination::Ephemeral(cmd)
+            } else {
+                Destination::Channel
+            },
+        ))
+    }
+
+    /// Writes the response as a reply to a message.
+    async fn for_reply(
+        http: &'a Http,
+        msg: &Message,
+        prompts: Prompts,
+        inference: &config::Inference,
+        command: &config::Command,
+    ) -> anyhow::Result<Outputter<'a>> {
+        // Direct messages can't have webhooks, so the bot responds as itself there
+        if let Some(username) = command
+            .webhook_username
+            .as_ref()
+            .filter(|_| msg.guild_id.is_some())
+        {
+            return Self::for_webhook(
+                http,
+                msg.channel_id,
+                msg.author.id,
+                prompts,
+                inference,
+                command,
+                username,
+            )
+            .await;
+        }
+
+        let use_embeds = inference.use_embeds;
+        let starting_message = msg
+            .channel_id
+            .send_message(http, |m| {
+                m.reference_message(msg)
+                    .allowed_mentions(|m| m.empty_roles().empty_users().empty_parse());
+                if use_embeds {
+                    m.set_embed(prompts.make_embed(0, &prompts.make_embed_description("")))
+                } else {
+                    m.content(prompts.make_markdown_message(""))
+                }
+            })
+            .await?;
+
+        Ok(Self::with_message(
+            http,
+            starting_message,
+            msg.author.id,
+            prompts,
+            inference,
+            Destination::Channel,
+        ))
+    }
+
+    /// Posts the response through a channel webhook under the command's persona.
+    async fn for_webhook(
+        http: &'a Http,
+        channel_id: ChannelId,
+        user_id: UserId,
+        prompts: Prompts,
+        inference: &config::Inference,
+        command: &config::Command,
+        username: &str,
+    ) -> anyhow::Result<Outputter<'a>> {
+        let webhook = channel_webhook(http, channel_id).await?;
+
+        let avatar_url = command.webhook_avatar_url.clone();
+        let starting_message = webhook
+            .execute(http, true, |m| {
+                m.username(username)
+                    .content(prompts.make_markdown_message(""))
+                    .allowed_mentions(|m| m.empty_roles().empty_users().empty_parse());
+                if let Some(avatar_url) = &avatar_url {
+                    m.avatar_url(avatar_url);
+                }
+                m
+            })
+            .await?
+            .context("webhook did not return the message it posted")?;
+
+        let mut outputter = Self::with_message(
+            http,
+            starting_message,
+            user_id,
+            prompts,
+            inference,
+            Destination::Webhook {
+                webhook,
+                username: username.to_string(),
+                avatar_url,
+            },
+        );
+        // Webhook messages are kept as plain text to keep their handling simple
+        outputter.use_embeds = false;
+        Ok(outputter)
+    }
+
+    /// Posts the response as a new message in the channel, rather than in reply to anything.
+    async fn for_channel(
+        http: &'a Http,
+        channel_id: ChannelId,
+        user_id: UserId,
+        prompts: Prompts,
+        inference: &config::Inference,
+        command: &config::Command,
+    ) -> anyhow::Result<Outputter<'a>> {
+        if let Some(username) = &command.webhook_username {
+            return Self::for_webhook(
+                http, channel_id, user_id, prompts, inference, command, username,
+            )
+            .await;
+        }
+
+        let use_embeds = inference.use_embeds;
+        let starting_message = channel_id
+            .send_message(http, |m| {
+                m.allowed_mentions(|m| m.empty_roles().empty_users().empty_parse());
+                if use_embeds {
+                    m.set_embed(prompts.make_embed(0, &prompts.make_embed_description("")))
+                } else {
+                    m.content(prompts.make_markdown_message(""))
+                }
+            })
+            .await?;
+
+        Ok(Self::with_message(
+            http,
+            starting_message,
+            user_id,
+            prompts,
+            inference,
+            Destination::Channel,
+        ))
+    }
+
+    /// Writes the response to an existing message, which is reset to show just the prompt.
+    async fn for_existing_message(
+        http: &'a Http,
+        mut message: Message,
+        user_id: UserId,
+        prompts: Prompts,
+        inference: &config::Inference,
+    ) -> anyhow::Result<Outputter<'a>> {
+        let use_embeds = inference.use_embeds;
+        message
+            .edit(http, |m| {
+                m.set_components(CreateComponents::default());
+                if use_embeds {
+                    m.set_embed(prompts.make_embed(0, &prompts.make_embed_description("")))
                 } else {
-                    chunks.push(word.to_string());
+                    m.content(prompts.make_markdown_message(""))
                 }
+            })
+            .await?;
+
+        Ok(Self::with_message(
+            http,
+            message,
+            user_id,
+            prompts,
+            inference,
+            Destination::Channel,
+        ))
+    }
+
+    /// Continues a previous response, appending to its messages.
+    async fn for_continuation(
+        http: &'a Http,
+        mut messages: Vec<Message>,
+        user_id: UserId,
+        prompts: Prompts,
+        inference: &config::Inference,
+        response: String,
+        replayed: String,
+    ) -> anyhow::Result<Outputter<'a>> {
+        anyhow::ensure!(
+            !messages.is_empty(),
+            "no message to continue the response in"
+        );
+        let first_id = messages[0].id;
+        if let (true, Some(last)) = (inference.allow_cancel, messages.last_mut()) {
+            add_cancel_button(http, first_id, last, user_id).await?;
+        }
+
+        let mut outputter = Self::with_message(
+            http,
+            messages.remove(0),
+            user_id,
+            prompts,
+            inference,
+            Destination::Channel,
+        );
+        outputter.messages.append(&mut messages);
+        outputter.message = response;
+        outputter.replayed = replayed;
+        outputter.update_chunks();
+        Ok(outputter)
+    }
+
+    fn with_message(
+        http: &'a Http,
+        message: Message,
+        user_id: UserId,
+        prompts: Prompts,
+        inference: &config::Inference,
+        destination: Destination<'a>,
+    ) -> Self {
+        Self {
+            http,
+
+            user_id,
+            messages: vec![message],
+            chunks: vec![],
+            synced_chunks: vec![],
+
+            message: String::new(),
+            replayed: String::new(),
+            prompts,
+            status: None,
+            notes: vec![],
+
+            in_terminal_state: false,
+            trim_incomplete_sentence: inference.trim_incomplete_sentence,
+            strike_on_cancel: inference.strike_on_cancel,
+            allow_cancel: inference.allow_cancel,
+            use_embeds: inference.use_embeds,
+            message_chunk_size: inference.message_chunk_size,
+            destination,
+
+            last_update: std::time::Instant::now(),
+            last_update_duration: std::time::Duration::from_millis(
+                inference.discord_message_update_interval_ms,
+            ),
+        }
+    }
+
+    fn update_chunks(&mut self) {
+        // This could be much more efficient but that's a problem for later
+        self.chunks = {
+            let chunk_size = if self.use_embeds {
+                Self::EMBED_CHUNK_SIZE
+            } else {
+                self.message_chunk_size
+            };
+            let mut markdown =
+                self.prompts
+                    .make_response(&self.replayed, &self.message, self.use_embeds);
+            if let Some(status) = &self.status {
+                markdown += &format!("\n*{status}*");
+            }
+            if !self.notes.is_empty() {
+                markdown += &format!("\n*{}*", self.notes.join(" · "));
+            }
+            let mut chunks = chunk_markdown(&markdown, chunk_size);
+
+            // Ephemeral responses can't be followed up with more messages, so cut them
+            // short, leaving room to say so
+            if matches!(self.destination, Destination::Ephemeral(_)) && chunks.len() > 1 {
+                const NOTICE: &str =
+                    "\n*(truncated: the response is too long for an ephemeral message)*";
+                chunks = chunk_markdown(&markdown, chunk_size.saturating_sub(NOTICE.len()));
+                chunks.truncate(1);
+                chunks[0] += NOTICE;
             }
 
             chunks
         };
+    }
 
-        if self.last_update.elapsed() > self.last_update_duration {
-            self.sync_messages_with_chunks().await?;
-            self.last_update = std::time::Instant::now();
+    /// Removes any text after the last sentence-ending punctuation in the response.
+    fn trim_to_last_sentence(&mut self) {
+        let response_start = if self.message.starts_with(&self.prompts.processed) {
+            self.prompts.processed.len()
+        } else {
+            0
+        };
+
+        if let Some(end) = self.message[response_start..].rfind(|c| matches!(c, '.' | '!' | '?')) {
+            self.message.truncate(response_start + end + 1);
         }
+    }
 
-        Ok(())
+    /// Updates the messages, but leaves them for a later update if Discord rate
+    /// limits us, updating less often from then on.
+    async fn try_sync_messages_with_chunks(&mut self) -> anyhow::Result<()> {
+        if self.has_trivial_changes() {
+            return Ok(());
+        }
+
+        match self.sync_messages_with_chunks().await {
+            Err(err) if util::is_rate_limited(&err) => {
+                self.widen_update_interval();
+                Ok(())
+            }
+            result => result,
+        }
     }
 
-    async fn error(&mut self, err: &str) -> anyhow::Result<()> {
-        self.on_error(err).await
+    /// Updates the messages, waiting and trying again if Discord rate limits us.
+    async fn sync_messages_with_chunks_despite_rate_limits(&mut self) -> anyhow::Result<()> {
+        for _ in 1..Self::RATE_LIMITED_ATTEMPTS {
+            match self.sync_messages_with_chunks().await {
+                Err(err) if util::is_rate_limited(&err) => {
+                    self.widen_update_interval();
+                    tokio::time::sleep(self.last_update_duration).await;
+                }
+                result => return result,
+            }
+        }
+        self.sync_messages_with_chunks().await
     }
 
-    async fn cancelled(&mut self) -> anyhow::Result<()> {
-        self.on_error("The generation was cancelled.").await
+    /// How long to wait between updates mid-generation. Every update edits the whole
+    /// response, so longer responses are updated less often, but never more often
+    /// than the configured interval.
+    fn update_interval(&self) -> std::time::Duration {
+        let doublings = ((self.message.len() / Self::INTERVAL_DOUBLING_LENGTH) as u32)
+            .min(Self::MAX_INTERVAL_DOUBLINGS);
+        (self.last_update_duration * 2u32.pow(doublings))
+            .min(Self::MAX_UPDATE_INTERVAL)
+            .max(self.last_update_duration)
     }
 
-    async fn finish(&mut self) -> anyhow::Result<()> {
-        for msg in &mut self.messages {
-            msg.edit(self.http, |m| m.set_components(CreateComponents::default()))
-                .await?;
-        }
+    /// Whether the response has changed too little since the messages were last
+    /// updated to be worth editing them. Statuses shown before the response starts
+    /// are always worth it.
+    fn has_trivial_changes(&self) -> bool {
+        !self.message.is_empty()
+            && self.chunks.len() == self.synced_chunks.len()
+            && self
+                .chunks
+                .iter()
+                .zip(&self.synced_chunks)
+                .all(|(chunk, synced)| chunk.len().abs_diff(synced.len()) < Self::MIN_UPDATE_DELTA)
+    }
 
-        self.sync_messages_with_chunks().await?;
+    /// Records that the message at `index` now shows `chunk`.
+    fn record_synced(synced_chunks: &mut Vec<String>, index: usize, chunk: &str) {
+        synced_chunks.resize(index, String::new());
+        synced_chunks.push(chunk.to_string());
+    }
 
-        Ok(())
+    fn widen_update_interval(&mut self) {
+        self.last_update_duration = (self.last_update_duration * 2).min(Self::MAX_UPDATE_INTERVAL);
+        tracing::warn!(
+            interval = ?self.last_update_duration,
+            "Rate limited while updating a response; updating less often"
+        );
     }
 
     async fn sync_messages_with_chunks(&mut self) -> anyhow::Result<()> {
+        match &self.destination {
+            Destination::Channel => {}
+            Destination::Ephemeral(cmd) => {
+                let Some(chunk) = self.chunks.first() else { return Ok(()); };
+                if self.synced_chunks.first() == Some(chunk) {
+                    return Ok(());
+                }
+                let embed = self.use_embeds.then(|| self.prompts.make_embed(0, chunk));
+                cmd.edit_original_interaction_response(self.http, |r| match embed {
+                    Some(embed) => r.set_embed(embed),
+                    None => r.content(chunk),
+                })
+                .await?;
+                Self::record_synced(&mut self.synced_chunks, 0, chunk);
+                return Ok(());
+            }
+            Destination::Webhook {
+                webhook,
+                username,
+                avatar_url,
+            } => {
+                if let Some((index, (msg, chunk))) = self
+                    .messages
+                    .iter_mut()
+                    .zip(self.chunks.iter())
+                    .enumerate()
+                    .last()
+                {
+                    if self.synced_chunks.get(index) != Some(chunk) {
+                        *msg = webhook
+                            .edit_message(self.http, msg.id, |m| m.content(chunk))
+                            .await?;
+                        Self::record_synced(&mut self.synced_chunks, index, chunk);
+                    }
+                }
+                for (index, chunk) in self.chunks.iter().enumerate().skip(self.messages.len()) {
+                    let msg = webhook
+                        .execute(self.http, true, |m| {
+                            m.username(username)
+                                .content(chunk)
+                                .allowed_mentions(|m| m.empty_roles().empty_users().empty_parse());
+                            if let Some(avatar_url) = avatar_url {
+                                m.avatar_url(avatar_url);
+                            }
+                            m
+                        })
+                        .await?
+                        .context("webhook did not return the message it posted")?;
+                    self.messages.push(msg);
+                    Self::record_synced(&mut self.synced_chunks, index, chunk);
+                }
+                return Ok(());
+            }
+        }
+
         // Update the last message with its latest state, then insert the remaining chunks in one go
-        if let Some((msg, chunk)) = self.messages.iter_mut().zip(self.chunks.iter()).last() {
-            msg.edit(self.http, |m| m.content(chunk)).await?;
+        if let Some((index, (msg, chunk))) = self
+            .messages
+            .iter_mut()
+            .zip(self.chunks.iter())
+            .enumerate()
+            .last()
+        {
+            if self.synced_chunks.get(index) != Some(chunk) {
+                let embed = self
+                    .use_embeds
+                    .then(|| self.prompts.make_embed(index, chunk));
+                msg.edit(self.http, |m| match embed {
+                    Some(embed) => m.set_embed(embed),
+                    None => m.content(chunk),
+                })
+                .await?;
+                Self::record_synced(&mut self.synced_chunks, index, chunk);
+            }
         }
 
         if self.chunks.len() <= self.messages.len() {
@@ -414,32 +3606,144 @@ impl<'a> Outputter<'a> {
 
         // Create new messages for the remaining chunks
         let Some(first_id) = self.messages.first().map(|m| m.id) else { return Ok(()); };
-        for chunk in self.chunks[self.messages.len()..].iter() {
-            let last = self.messages.last_mut().unwrap();
-            let msg = last.reply(self.http, chunk).await?;
+        for (index, chunk) in self.chunks.iter().enumerate().skip(self.messages.len()) {
+            let last = self.messages.last().unwrap();
+            let embed = self
+                .use_embeds
+                .then(|| self.prompts.make_embed(index, chunk));
+            let msg = last
+                .channel_id
+                .send_message(self.http, |m| {
+                    m.reference_message(last)
+                        .allowed_mentions(|m| m.empty_roles().empty_users().empty_parse());
+                    match embed {
+                        Some(embed) => m.set_embed(embed),
+                        None => m.content(chunk),
+                    }
+                })
+                .await?;
             self.messages.push(msg);
+            Self::record_synced(&mut self.synced_chunks, index, chunk);
         }
 
         // Add the cancel button to the last message
-        if let Some(last) = self.messages.last_mut() {
+        if let (true, Some(last)) = (self.allow_cancel, self.messages.last_mut()) {
             add_cancel_button(self.http, first_id, last, self.user_id).await?;
         }
 
         Ok(())
     }
 
-    async fn on_error(&mut self, error_message: &str) -> anyhow::Result<()> {
+    /// Deletes the messages past the last chunk, which are left over when the
+    /// response gets shorter. The first message is always kept.
+    async fn remove_extra_messages(&mut self) -> anyhow::Result<()> {
+        let keep = self.chunks.len().max(1).min(self.messages.len());
+        for msg in self.messages.split_off(keep) {
+            match &self.destination {
+                Destination::Channel => msg.channel_id.delete_message(self.http, msg.id).await?,
+                Destination::Webhook { webhook, .. } => {
+                    webhook.delete_message(self.http, msg.id).await?
+                }
+                Destination::Ephemeral(_) => {}
+            }
+        }
+        self.synced_chunks.truncate(keep);
+        Ok(())
+    }
+
+    /// Strikes out the response so far and replies with the message, styled
+    /// according to why the generation ended.
+    async fn on_error(&mut self, error_message: &str, severity: Severity) -> anyhow::Result<()> {
+        let notice = self.use_embeds.then(|| severity.make_embed(error_message));
+        let plain_notice = severity.format(error_message);
+
+        match &self.destination {
+            Destination::Channel => {}
+            Destination::Ephemeral(cmd) => {
+                let cut_chunk = format!("~~{}~~", self.chunks.first().map_or("", |c| c.as_str()));
+                let embed = self.use_embeds.then(|| {
+                    let mut embed = self.prompts.make_embed(0, &cut_chunk);
+                    embed.colour(severity.colour());
+                    embed
+                });
+                cmd.edit_original_interaction_response(self.http, |r| match embed {
+                    Some(embed) => r.set_embed(embed),
+                    None => r.content(cut_chunk),
+                })
+                .await?;
+                cmd.create_followup_message(self.http, |m| {
+                    m.ephemeral(true);
+                    match notice {
+                        Some(embed) => m.add_embed(embed),
+                        None => m.content(plain_notice),
+                    }
+                })
+                .await?;
+
+                self.in_terminal_state = true;
+                return Ok(());
+            }
+            Destination::Webhook {
+                webhook,
+                username,
+                avatar_url,
+            } => {
+                for msg in &mut self.messages {
+                    let cut_content = format!("~~{}~~", msg.content);
+                    *msg = webhook
+                        .edit_message(self.http, msg.id, |m| m.content(cut_content))
+                        .await?;
+                }
+                webhook
+                    .execute(self.http, false, |m| {
+                        m.username(username)
+                            .content(plain_notice)
+                            .allowed_mentions(|a| a.empty_parse());
+                        if let Some(avatar_url) = avatar_url {
+                            m.avatar_url(avatar_url);
+                        }
+                        m
+                    })
+                    .await?;
+
+                self.in_terminal_state = true;
+                return Ok(());
+            }
+        }
+
         for msg in &mut self.messages {
+            let cut_embed = match msg.embeds.first() {
+                Some(embed) if self.use_embeds => {
+                    let description = embed.description.as_deref().unwrap_or_default();
+                    let cut_description = format!("~~{description}~~");
+                    let mut embed = CreateEmbed::from(embed.clone());
+                    embed.description(cut_description).colour(severity.colour());
+                    Some(embed)
+                }
+                _ => None,
+            };
             let cut_content = format!("~~{}~~", msg.content);
             msg.edit(self.http, |m| {
-                m.set_components(CreateComponents::default())
-                    .content(cut_content)
+                m.set_components(CreateComponents::default());
+                match cut_embed {
+                    Some(embed) => m.set_embed(embed),
+                    None => m.content(cut_content),
+                }
             })
             .await?;
         }
 
-        let Some(last) = self.messages.last_mut() else { return Ok(()); };
-        last.reply(self.http, error_message).await?;
+        let Some(last) = self.messages.last() else { return Ok(()); };
+        last.channel_id
+            .send_message(self.http, |m| {
+                m.reference_message(last)
+                    .allowed_mentions(|a| a.empty_parse());
+                match notice {
+                    Some(embed) => m.set_embed(embed),
+                    None => m.content(plain_notice),
+                }
+            })
+            .await?;
 
         self.in_terminal_state = true;
 
@@ -447,6 +3751,176 @@ impl<'a> Outputter<'a> {
     }
 }
 
+#[async_trait]
+impl output::OutputSink for Outputter<'_> {
+    async fn waiting(&mut self) -> anyhow::Result<()> {
+        // Show that the bot is typing. Ephemeral and webhook responses aren't posted
+        // by the bot, so they don't do this.
+        if let (Destination::Channel, Some(first)) = (&self.destination, self.messages.first()) {
+            first.channel_id.broadcast_typing(self.http).await.ok();
+        }
+        Ok(())
+    }
+
+    async fn queue_position(&mut self, position: usize) -> anyhow::Result<()> {
+        if self.in_terminal_state || !self.message.is_empty() {
+            return Ok(());
+        }
+
+        self.status = Some(format!("You are #{position} in the queue…"));
+        self.update_chunks();
+        self.try_sync_messages_with_chunks().await
+    }
+
+    async fn prompt_progress(&mut self, fed: usize, total: usize) -> anyhow::Result<()> {
+        if self.in_terminal_state {
+            return Ok(());
+        }
+
+        // Once the prompt has been read, the response follows straight away
+        self.status = (fed < total).then(|| format!("Reading prompt… {}%", fed * 100 / total));
+        self.update_chunks();
+
+        if self.last_update.elapsed() > self.update_interval() {
+            self.try_sync_messages_with_chunks().await?;
+            self.last_update = std::time::Instant::now();
+        }
+
+        Ok(())
+    }
+
+    fn push_replayed(&mut self, text: &str) {
+        self.replayed.push_str(text);
+    }
+
+    async fn push_token(&mut self, token: &str) -> anyhow::Result<()> {
+        if self.in_terminal_state {
+            return Ok(());
+        }
+
+        if self.message.is_empty() {
+            self.status = None;
+
+            // Add the cancellation button when we receive the first token, unless the
+            // response isn't posted by the bot and can't be cancelled
+            if self.allow_cancel && matches!(self.destination, Destination::Channel) {
+                if let Some(first) = self.messages.first_mut() {
+                    add_cancel_button(self.http, first.id, first, self.user_id).await?;
+                }
+            }
+        }
+
+        self.message += token;
+        self.update_chunks();
+
+        if self.last_update.elapsed() > self.update_interval() {
+            self.try_sync_messages_with_chunks().await?;
+            self.last_update = std::time::Instant::now();
+        }
+
+        Ok(())
+    }
+
+    fn add_note(&mut self, note: String) {
+        self.notes.push(note);
+    }
+
+    async fn error(&mut self, message: &str) -> anyhow::Result<()> {
+        self.on_error(message, Severity::Error).await
+    }
+
+    async fn cancelled(&mut self) -> anyhow::Result<()> {
+        if !self.strike_on_cancel {
+            // Keep what was generated, as it may still be useful
+            self.notes.push("(cancelled)".to_string());
+            self.in_terminal_state = true;
+            return self.finalize(false).await;
+        }
+
+        // Only the user who started a generation can cancel it
+        let message = format!("The generation was cancelled by <@{}>.", self.user_id);
+        self.on_error(&message, Severity::Cancellation).await
+    }
+
+    async fn finalize(&mut self, truncated: bool) -> anyhow::Result<()> {
+        if truncated {
+            if self.trim_incomplete_sentence {
+                self.trim_to_last_sentence();
+            }
+            self.notes.push("(truncated)".to_string());
+        }
+        self.update_chunks();
+        // Trimming can take the response back into an earlier message
+        self.remove_extra_messages().await?;
+
+        if matches!(self.destination, Destination::Channel) {
+            for msg in &mut self.messages {
+                msg.edit(self.http, |m| m.set_components(CreateComponents::default()))
+                    .await?;
+            }
+        }
+
+        // The final state of the response has to make it through
+        self.sync_messages_with_chunks_despite_rate_limits().await?;
+
+        Ok(())
+    }
+}
+
+/// The name of the webhooks llmcord creates to post as command personas
+const WEBHOOK_NAME: &str = "llmcord";
+
+/// Finds the webhook llmcord previously created in the channel, or creates it.
+async fn channel_webhook(http: &Http, channel_id: ChannelId) -> anyhow::Result<Webhook> {
+    let existing = channel_id
+        .webhooks(http)
+        .await
+        .context("failed to list the channel's webhooks; does the bot have Manage Webhooks?")?
+        .into_iter()
+        .find(|w| w.name.as_deref() == Some(WEBHOOK_NAME) && w.token.is_some());
+    if let Some(webhook) = existing {
+        return Ok(webhook);
+    }
+
+    Ok(channel_id
+        .create_webhook(http, WEBHOOK_NAME)
+        .await
+        .context("failed to create a webhook; does the bot have Manage Webhooks?")?)
+}
+
+async fn add_finished_buttons(
+    http: &Http,
+    first_id: MessageId,
+    msg: &mut Message,
+    user_id: UserId,
+    regenerate: bool,
+    continue_: bool,
+) -> anyhow::Result<()> {
+    Ok(msg
+        .edit(http, |r| {
+            let mut components = CreateComponents::default();
+            components.create_action_row(|r| {
+                if regenerate {
+                    r.create_button(|b| {
+                        b.custom_id(format!("regenerate#{first_id}#{user_id}"))
+                            .style(component::ButtonStyle::Secondary)
+                            .label("Regenerate")
+                    });
+                }
+                if continue_ {
+                    r.create_button(|b| {
+                        b.custom_id(format!("continue#{first_id}#{user_id}"))
+                            .style(component::ButtonStyle::Primary)
+                            .label("Continue")
+                    });
+                }
+                r
+            });
+            r.set_components(components)
+        })
+        .await?)
+}
+
 async fn add_cancel_button(
     http: &Http,
     first_id: MessageId,
@@ -467,3 +3941,50 @@ async fn add_cancel_button(
         })
         .await?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The prompts of a chat turn, as shown without the template.
+    fn chat_prompts() -> Prompts {
+        Prompts {
+            show_prompt_template: false,
+            display_style: config::PromptDisplayStyle::Strikethrough,
+            processed: "User: How are you?\nBot:".to_string(),
+            shown: "User: How are you?\nBot:".to_string(),
+            user: "How are you?".to_string(),
+            template: "User: {{PROMPT}}\nBot:".to_string(),
+            global_prefix: String::new(),
+            global_suffix: String::new(),
+        }
+    }
+
+    const REPLAYED: &str = "User: Hello\nBot: Hi there.\n";
+    const MESSAGE: &str = "User: How are you?\nBot: Very well.";
+
+    #[test]
+    fn replayed_conversation_comes_before_the_response() {
+        let prompts = chat_prompts();
+
+        assert_eq!(
+            prompts.make_response(REPLAYED, MESSAGE, false),
+            "User: Hello\nBot: Hi there.\n**How are you?** Very well."
+        );
+        assert_eq!(
+            prompts.make_response(REPLAYED, MESSAGE, true),
+            "User: Hello\nBot: Hi there.\nVery well."
+        );
+    }
+
+    #[test]
+    fn replayed_conversation_does_not_affect_the_prompt() {
+        let prompts = chat_prompts();
+
+        for use_embeds in [false, true] {
+            let without = prompts.make_response("", MESSAGE, use_embeds);
+            let with = prompts.make_response(REPLAYED, MESSAGE, use_embeds);
+            assert_eq!(with, format!("{}\n{without}", REPLAYED.trim_end()));
+        }
+    }
+}
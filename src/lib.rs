@@ -0,0 +1,203 @@
+//! The core of llmcord: configuration, model loading and the generation thread,
+//! along with the Discord [handler::Handler] that ties them together.
+//!
+//! The `llmcord` binary is a thin wrapper around [load_models] and [run]; other
+//! frontends can drive a [Generator] directly.
+
+pub mod config;
+pub mod generation;
+pub mod handler;
+pub mod metrics;
+pub mod output;
+pub mod stats;
+
+mod constant;
+mod download;
+mod markdown;
+mod openai;
+mod samplers;
+mod schedule;
+mod util;
+
+pub use config::Configuration;
+pub use download::download_model_if_missing;
+pub use generation::{Generator, InferenceError, RemoteModel, Request, Token};
+
+use anyhow::Context as AnyhowContext;
+use serenity::{model::prelude::*, Client};
+use std::{collections::HashMap, sync::Arc};
+
+/// Loads every local model in the configuration, with as many instances of each
+/// as requested. Each model is loaded independently, so that the caller can decide
+/// what to do about the ones that failed.
+///
+/// If a model with a `fallback` fails to load, the fallback is loaded instead, and
+/// replaces the model in the configuration.
+pub fn load_models(
+    config: &mut Configuration,
+) -> HashMap<String, anyhow::Result<Vec<Box<dyn llm::Model>>>> {
+    config
+        .models
+        .iter_mut()
+        .filter(|(_, model)| model.backend == config::Backend::Local)
+        .map(|(name, model)| {
+            let instances = match load_instances(name, model) {
+                Err(err) => match model.fallback.as_deref() {
+                    Some(fallback) => {
+                        tracing::error!(model = %name, "{err:?}");
+                        tracing::warn!(
+                            model = %name,
+                            path = %fallback.path.display(),
+                            "Loading the model's fallback instead"
+                        );
+                        let instances = load_instances(name, fallback).map_err(|fallback_err| {
+                            anyhow::anyhow!(
+                                "{err:#}\nIts fallback failed to load too: {fallback_err:#}"
+                            )
+                        });
+                        if instances.is_ok() {
+                            let mut fallback = *model.fallback.take().unwrap();
+                            fallback.is_fallback = true;
+                            *model = fallback;
+                        }
+                        instances
+                    }
+                    None => Err(err),
+                },
+                instances => instances,
+            };
+            (name.clone(), instances)
+        })
+        .collect()
+}
+
+/// Loads as many instances of the model as it asks for.
+fn load_instances(name: &str, model: &config::Model) -> anyhow::Result<Vec<Box<dyn llm::Model>>> {
+    anyhow::ensure!(
+        model.instances > 0,
+        "models.{name}.instances must be at least 1"
+    );
+    (0..model.instances)
+        .map(|_| load_model(model))
+        .collect::<anyhow::Result<_>>()
+        .with_context(|| {
+            format!(
+                "failed to load model `{name}` from {} (architecture: {})",
+                model.path.display(),
+                model.architecture
+            )
+        })
+}
+
+/// Loads a single instance of the model described by the configuration.
+pub fn load_model(config: &config::Model) -> anyhow::Result<Box<dyn llm::Model>> {
+    for path in &config.lora_paths {
+        anyhow::ensure!(
+            path.exists(),
+            "The LoRA adapter at {} does not exist",
+            path.display()
+        );
+    }
+
+    let model = llm::load_dynamic(
+        config.architecture(),
+        &config.path,
+        llm::TokenizerSource::Embedded,
+        llm::ModelParameters {
+            prefer_mmap: config.prefer_mmap,
+            context_size: config.context_token_length,
+            use_gpu: config.use_gpu,
+            gpu_layers: config.gpu_layers,
+            lora_adapters: (!config.lora_paths.is_empty()).then(|| config.lora_paths.clone()),
+            ..Default::default()
+        },
+        llm::load_progress_callback_stdout,
+    )?;
+
+    if !config.lora_paths.is_empty() {
+        let names: Vec<_> = config
+            .lora_paths
+            .iter()
+            .map(|p| p.file_name().unwrap_or(p.as_os_str()).to_string_lossy())
+            .collect();
+        tracing::info!(adapters = %names.join(", "), "Applied LoRA adapters");
+    }
+
+    Ok(model)
+}
+
+/// Connects to Discord and serves the configured commands until the client stops.
+/// Commands that use a model in `model_load_errors` reply with its error.
+pub async fn run(
+    config: Configuration,
+    models: HashMap<String, Vec<Box<dyn llm::Model>>>,
+    model_load_errors: HashMap<String, String>,
+) -> anyhow::Result<()> {
+    let mut intents = GatewayIntents::default();
+    if config.needs_message_content() {
+        intents |= GatewayIntents::MESSAGE_CONTENT;
+    }
+    if config.inference.respond_to_mentions {
+        intents |= GatewayIntents::GUILD_MESSAGES;
+    }
+    if config.inference.allow_dms {
+        intents |= GatewayIntents::DIRECT_MESSAGES;
+    }
+    // For cancelling generations by reacting to their responses
+    if config.inference.allow_cancel {
+        intents |= GatewayIntents::GUILD_MESSAGE_REACTIONS;
+        if config.inference.allow_dms {
+            intents |= GatewayIntents::DIRECT_MESSAGE_REACTIONS;
+        }
+    }
+
+    let token = config
+        .authentication
+        .discord_token
+        .clone()
+        .with_context(|| {
+            format!(
+                "Expected a Discord token in the {} environment variables, or in \
+                 authentication.discord_token in config (the environment takes precedence)",
+                Configuration::DISCORD_TOKEN_ENV_VARS.join(" or ")
+            )
+        })?;
+
+    let handler = Arc::new(handler::Handler::new(config, models, model_load_errors));
+    let shutdown = handler.shutdown_handle();
+
+    let mut client = Client::builder(token, intents)
+        .event_handler_arc(handler.clone())
+        .await
+        .context("Error creating client")?;
+
+    tokio::spawn(handler.run_schedules(client.cache_and_http.http.clone()));
+
+    // On Ctrl-C, let the generations in progress wrap up before disconnecting
+    let shard_manager = client.shard_manager.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            tracing::info!("Shutting down...");
+            shutdown.shutdown(std::time::Duration::from_secs(10)).await;
+            shard_manager.lock().await.shutdown_all().await;
+        }
+    });
+
+    if let Err(why) = client.start().await {
+        use serenity::gateway::GatewayError;
+        match why {
+            serenity::Error::Gateway(GatewayError::DisallowedGatewayIntents) => tracing::error!(
+                "Discord refused the Message Content intent, which commands that read the \
+                 channel's history need. Enable it under Bot > Privileged Gateway Intents \
+                 in the Discord developer portal, or remove {{{{HISTORY}}}} from your commands."
+            ),
+            serenity::Error::Gateway(GatewayError::InvalidAuthentication) => tracing::error!(
+                "Discord rejected the token. Reset it under Bot in the Discord developer \
+                 portal, and update the configuration with the new one."
+            ),
+            why => tracing::error!("Client error: {why:?}"),
+        }
+    }
+
+    Ok(())
+}
@@ -1,47 +1,116 @@
-use anyhow::Context as AnyhowContext;
-use serenity::{model::prelude::*, Client};
-
-mod config;
-mod constant;
-mod generation;
-mod handler;
-mod util;
-
-use config::Configuration;
+use llmcord::Configuration;
+use std::{collections::HashMap, path::PathBuf};
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let config = Configuration::load()?;
-
-    let model = llm::load_dynamic(
-        config.model.architecture(),
-        &config.model.path,
-        llm::TokenizerSource::Embedded,
-        llm::ModelParameters {
-            prefer_mmap: config.model.prefer_mmap,
-            context_size: config.model.context_token_length,
-            use_gpu: config.model.use_gpu,
-            gpu_layers: config.model.gpu_layers,
-            ..Default::default()
-        },
-        llm::load_progress_callback_stdout,
-    )?;
-
-    let mut client = Client::builder(
-        config
-            .authentication
-            .discord_token
-            .as_deref()
-            .context("Expected authentication.discord_token to be filled in config")?,
-        GatewayIntents::default(),
-    )
-    .event_handler(handler::Handler::new(config, model))
-    .await
-    .context("Error creating client")?;
-
-    if let Err(why) = client.start().await {
-        println!("Client error: {why:?}");
+    // Log llmcord's own events at `info` and everything else at `warn` by default;
+    // `RUST_LOG` overrides this (e.g. `RUST_LOG=llmcord=debug`)
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new("warn,llmcord=info")),
+        )
+        .init();
+
+    let mut config = Configuration::load(config_path()?.as_deref())?;
+    for (name, model) in &config.models {
+        match llmcord::download_model_if_missing(model).await {
+            // The fallback will be loaded instead
+            Err(err) if model.fallback.is_some() => {
+                tracing::error!(model = %name, "{err:?}");
+            }
+            result => result?,
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(port) = config.metrics.port {
+        tokio::spawn(async move {
+            if let Err(err) = llmcord::metrics::serve(port).await {
+                tracing::error!("Metrics server error: {err:?}");
+            }
+        });
+    }
+
+    let mut models = HashMap::new();
+    let mut model_load_errors = HashMap::new();
+    for (name, result) in llmcord::load_models(&mut config) {
+        match result {
+            Ok(instances) => {
+                if let Some(instance) = instances.first() {
+                    let banner = model_banner(&name, &config.models[&name], instance.as_ref());
+                    tracing::info!("Loaded model\n{banner}");
+                }
+                models.insert(name, instances);
+            }
+            Err(err) if config.inference.start_on_model_load_failure => {
+                tracing::error!(model = %name, "{err:?}");
+                model_load_errors.insert(name, format!("{err:#}"));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    llmcord::run(config, models, model_load_errors).await
+}
+
+/// Describes a loaded model, so that it's clear from the logs which model is
+/// running and how it was set up.
+fn model_banner(name: &str, config: &llmcord::config::Model, model: &dyn llm::Model) -> String {
+    const GIB: f64 = (1u64 << 30) as f64;
+
+    let file_size = std::fs::metadata(&config.path)
+        .map(|m| format!("{:.2} GiB", m.len() as f64 / GIB))
+        .unwrap_or_else(|_| "unknown".to_string());
+    let mut lines = vec![
+        format!(
+            "  {name}{}",
+            if config.is_fallback {
+                " (fallback)"
+            } else {
+                ""
+            }
+        ),
+        format!("    path:         {}", config.path.display()),
+        format!("    architecture: {}", config.architecture),
+        format!("    file size:    {file_size}"),
+        format!("    vocabulary:   {} tokens", model.tokenizer().len()),
+        format!("    context:      {} tokens", model.context_size()),
+        format!("    instances:    {}", config.instances),
+    ];
+    if !config.lora_paths.is_empty() {
+        let adapters: Vec<_> = config
+            .lora_paths
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        lines.push(format!("    LoRA:         {}", adapters.join(", ")));
+    }
+    lines.join("\n")
+}
+
+/// The path to the configuration file, from `--config <path>` or the
+/// [Configuration::PATH_ENV_VAR] environment variable, in that order.
+fn config_path() -> anyhow::Result<Option<PathBuf>> {
+    let mut path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if let Some(value) = arg.strip_prefix("--config=") {
+            path = Some(PathBuf::from(value));
+        } else if arg == "--config" {
+            let value = args.next().ok_or_else(|| {
+                anyhow::anyhow!("--config needs the path to a configuration file")
+            })?;
+            path = Some(PathBuf::from(value));
+        } else {
+            anyhow::bail!("unknown argument `{arg}`; the only argument is `--config <path>`");
+        }
     }
 
-    Ok(())
+    Ok(path.or_else(|| {
+        std::env::var_os(Configuration::PATH_ENV_VAR)
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from)
+    }))
 }
@@ -0,0 +1,245 @@
+//! Splitting Markdown responses into chunks that fit in Discord messages.
+
+const FENCE: &str = "```";
+/// What closes a code block left open at the end of a chunk
+const CLOSING_FENCE: &str = "\n```";
+
+/// Splits the text into chunks of at most `chunk_size` bytes at spaces. Words
+/// longer than a chunk are split across chunks. Code blocks that span chunks are
+/// closed and reopened so that each chunk renders correctly by itself, and the
+/// fences count towards the chunk's size. Only a `chunk_size` too small to hold
+/// the fences and a single character can be overshot.
+pub fn chunk_markdown(text: &str, chunk_size: usize) -> Vec<String> {
+    let chunk_size = chunk_size.max(1);
+
+    let mut chunks: Vec<String> = vec![];
+    if text.is_empty() {
+        return chunks;
+    }
+
+    let mut chunk = Chunk::default();
+    for (index, word) in text.split(' ').enumerate() {
+        // The space between two chunks is dropped
+        let separator = if index == 0 || (chunk.content.is_empty() && !chunks.is_empty()) {
+            ""
+        } else {
+            " "
+        };
+        if chunk.fits(separator, word, chunk_size) {
+            chunk.push(separator, word);
+            continue;
+        }
+
+        // Start a new chunk rather than overflow this one
+        if !chunk.content.is_empty() {
+            let next = chunk.next();
+            chunks.push(std::mem::replace(&mut chunk, next).finish());
+            if chunk.fits("", word, chunk_size) {
+                chunk.push("", word);
+                continue;
+            }
+        }
+
+        // The word doesn't fit in a chunk by itself, so it takes as many as it needs
+        let mut rest = word;
+        loop {
+            let (piece, remainder) = rest.split_at(chunk.room_for(rest, chunk_size));
+            chunk.push("", piece);
+            rest = remainder;
+            if rest.is_empty() {
+                break;
+            }
+            let next = chunk.next();
+            chunks.push(std::mem::replace(&mut chunk, next).finish());
+        }
+    }
+    chunks.push(chunk.finish());
+
+    chunks
+}
+
+/// A chunk being built, along with the code blocks it starts and ends in.
+#[derive(Default)]
+struct Chunk {
+    /// The language of the code block the chunk starts in, which is reopened at
+    /// its start
+    reopened: Option<String>,
+    content: String,
+    /// The language of the code block the content ends in, which is closed at
+    /// the chunk's end
+    open: Option<String>,
+}
+impl Chunk {
+    fn prefix_len(&self) -> usize {
+        self.reopened
+            .as_ref()
+            .map_or(0, |language| FENCE.len() + language.len() + 1)
+    }
+
+    /// Whether the piece fits after the separator without the finished chunk
+    /// going over `chunk_size`.
+    fn fits(&self, separator: &str, piece: &str, chunk_size: usize) -> bool {
+        let closing = match fence_state(self.open.clone(), piece) {
+            Some(_) => CLOSING_FENCE.len(),
+            None => 0,
+        };
+        self.prefix_len() + self.content.len() + separator.len() + piece.len() + closing
+            <= chunk_size
+    }
+
+    /// How many bytes from the start of the word fit in the chunk, at a character
+    /// boundary. At least a character is taken, so that chunking always progresses.
+    fn room_for(&self, word: &str, chunk_size: usize) -> usize {
+        if self.fits("", word, chunk_size) {
+            return word.len();
+        }
+
+        let char_boundary_before = |mut end: usize| {
+            end = end.min(word.len());
+            while !word.is_char_boundary(end) {
+                end -= 1;
+            }
+            end
+        };
+        let room = chunk_size.saturating_sub(self.prefix_len() + self.content.len());
+        let mut end = char_boundary_before(room);
+        if !self.fits("", &word[..end], chunk_size) {
+            // The piece leaves a code block open, so leave room to close it
+            end = char_boundary_before(room.saturating_sub(CLOSING_FENCE.len()));
+        }
+        if end == 0 {
+            end = word.chars().next().map_or(word.len(), char::len_utf8);
+        }
+        end
+    }
+
+    fn push(&mut self, separator: &str, piece: &str) {
+        self.content.push_str(separator);
+        self.content.push_str(piece);
+        self.open = fence_state(self.open.take(), piece);
+    }
+
+    /// The chunk that follows this one, which reopens the code block this one ends in.
+    fn next(&self) -> Self {
+        Self {
+            reopened: self.open.clone(),
+            content: String::new(),
+            open: self.open.clone(),
+        }
+    }
+
+    fn finish(self) -> String {
+        let mut chunk = String::new();
+        if let Some(language) = &self.reopened {
+            chunk += &format!("{FENCE}{language}\n");
+        }
+        chunk += &self.content;
+        if self.open.is_some() {
+            chunk += CLOSING_FENCE;
+        }
+        chunk
+    }
+}
+
+/// The language of the code block that's open after the text, given the code
+/// block that was open before it.
+fn fence_state(mut open: Option<String>, text: &str) -> Option<String> {
+    let mut rest = text;
+    while let Some(index) = rest.find(FENCE) {
+        rest = &rest[index + FENCE.len()..];
+        open = match open {
+            Some(_) => None,
+            None => Some(
+                rest.split(char::is_whitespace)
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+            ),
+        };
+    }
+    open
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_text_has_no_chunks() {
+        assert!(chunk_markdown("", 10).is_empty());
+    }
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        assert_eq!(chunk_markdown("Hello, world", 100), vec!["Hello, world"]);
+    }
+
+    #[test]
+    fn text_of_exactly_the_chunk_size_is_one_chunk() {
+        assert_eq!(chunk_markdown("aaaa bbbb", 9), vec!["aaaa bbbb"]);
+    }
+
+    #[test]
+    fn text_one_byte_over_the_chunk_size_is_split_at_the_space() {
+        assert_eq!(chunk_markdown("aaaa bbbbb", 9), vec!["aaaa", "bbbbb"]);
+    }
+
+    #[test]
+    fn words_longer_than_the_chunk_size_are_split() {
+        assert_eq!(
+            chunk_markdown("hi abcdefghij", 4),
+            vec!["hi", "abcd", "efgh", "ij"]
+        );
+    }
+
+    #[test]
+    fn long_words_are_split_at_character_boundaries() {
+        // Each character is 3 bytes long
+        let chunks = chunk_markdown("日本語のテキスト", 7);
+
+        assert!(chunks.iter().all(|c| c.len() <= 7));
+        assert_eq!(chunks.concat(), "日本語のテキスト");
+    }
+
+    #[test]
+    fn long_text_fills_each_chunk() {
+        // Twenty words of "word" and the spaces between them make 99 bytes
+        let text = vec!["word"; 1000].join(" ");
+
+        let chunks = chunk_markdown(&text, 100);
+
+        assert_eq!(chunks.len(), 50);
+        assert!(chunks.iter().all(|c| c.len() <= 100));
+        assert_eq!(chunks.join(" "), text);
+    }
+
+    #[test]
+    fn code_blocks_are_reopened_with_their_language() {
+        let code = vec!["let value = compute(1, 2);"; 112].join("\n");
+        assert!(code.len() >= 3000);
+        let text = format!("```rust\n{code}\n```");
+
+        let chunks = chunk_markdown(&text, 2000);
+
+        assert_eq!(chunks.len(), 2);
+        for chunk in &chunks {
+            assert!(chunk.starts_with("```rust\n"), "{chunk}");
+            assert!(chunk.ends_with("\n```"), "{chunk}");
+            assert_eq!(chunk.matches(FENCE).count(), 2, "{chunk}");
+        }
+    }
+
+    #[test]
+    fn code_block_fences_count_towards_the_size() {
+        let code = vec!["let x = 1;"; 400].join("\n");
+        let text = format!("Here:\n```rust\n{code}\n```");
+
+        for chunk_size in [50, 333, 1999, 2000] {
+            let chunks = chunk_markdown(&text, chunk_size);
+            assert!(
+                chunks.iter().all(|c| c.len() <= chunk_size),
+                "a chunk is longer than {chunk_size} bytes"
+            );
+        }
+    }
+}
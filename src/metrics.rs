@@ -0,0 +1,147 @@
+//! Counters for the generations, which can be scraped by Prometheus when
+//! llmcord is built with the `metrics` feature.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// The metrics for this process.
+pub static METRICS: Metrics = Metrics::new();
+
+/// The upper bounds of the generation duration histogram's buckets, in seconds
+const DURATION_BUCKETS: [f64; 8] = [1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+pub struct Metrics {
+    requests: AtomicU64,
+    tokens: AtomicU64,
+    cancellations: AtomicU64,
+    errors: AtomicU64,
+    durations: Mutex<Histogram>,
+}
+struct Histogram {
+    /// The number of observations in each bucket (not cumulative)
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            tokens: AtomicU64::new(0),
+            cancellations: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            durations: Mutex::new(Histogram {
+                buckets: [0; DURATION_BUCKETS.len()],
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cancellation(&self) {
+        self.cancellations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a generation that ran to completion.
+    pub fn generation(&self, tokens: usize, duration: Duration) {
+        self.tokens.fetch_add(tokens as u64, Ordering::Relaxed);
+
+        let seconds = duration.as_secs_f64();
+        let mut durations = self.durations.lock().unwrap();
+        if let Some(index) = DURATION_BUCKETS.iter().position(|b| seconds <= *b) {
+            durations.buckets[index] += 1;
+        }
+        durations.sum += seconds;
+        durations.count += 1;
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        let mut counter = |name: &str, help: &str, value: &AtomicU64| {
+            output += &format!(
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {}\n",
+                value.load(Ordering::Relaxed)
+            );
+        };
+        counter(
+            "llmcord_requests_total",
+            "Generations requested.",
+            &self.requests,
+        );
+        counter(
+            "llmcord_tokens_generated_total",
+            "Tokens generated.",
+            &self.tokens,
+        );
+        counter(
+            "llmcord_cancellations_total",
+            "Generations cancelled by their users.",
+            &self.cancellations,
+        );
+        counter(
+            "llmcord_errors_total",
+            "Generations that failed.",
+            &self.errors,
+        );
+
+        let name = "llmcord_generation_duration_seconds";
+        let durations = self.durations.lock().unwrap();
+        output += &format!("# HELP {name} How long generations took.\n# TYPE {name} histogram\n");
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(durations.buckets) {
+            cumulative += count;
+            output += &format!("{name}_bucket{{le=\"{bound}\"}} {cumulative}\n");
+        }
+        output += &format!("{name}_bucket{{le=\"+Inf\"}} {}\n", durations.count);
+        output += &format!("{name}_sum {}\n", durations.sum);
+        output += &format!("{name}_count {}\n", durations.count);
+
+        output
+    }
+}
+
+/// Serves the metrics over HTTP on the given port until the process exits.
+/// Every request gets the metrics, regardless of its path.
+#[cfg(feature = "metrics")]
+pub async fn serve(port: u16) -> anyhow::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+    tracing::info!(port, "Serving metrics");
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            // We don't care what was asked for, but the request needs to be read
+            // before responding
+            let mut request = [0; 1024];
+            if stream.read(&mut request).await.is_err() {
+                return;
+            }
+
+            let body = METRICS.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.ok();
+        });
+    }
+}
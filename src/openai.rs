@@ -0,0 +1,199 @@
+//! Generation with a model served by an OpenAI-compatible server, for running
+//! llmcord as a frontend to a separate inference server.
+
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::generation::{InferenceError, RemoteModel, Request, StopSequenceDetector, Token};
+
+/// A piece of a streamed completion.
+#[derive(Deserialize)]
+struct CompletionChunk {
+    choices: Vec<CompletionChoice>,
+}
+#[derive(Deserialize)]
+struct CompletionChoice {
+    text: String,
+    finish_reason: Option<String>,
+}
+
+/// Streams the completion of the request's prompt from the server, sending its
+/// tokens like the generation threads do. Stop sequences are detected here
+/// rather than by the server, as servers limit how many there can be.
+pub(crate) async fn generate(
+    remote: &RemoteModel,
+    request: &Request,
+    token_tx: &flume::Sender<Token>,
+    cancelled: &AtomicBool,
+    shutdown: &AtomicBool,
+) -> Result<(), InferenceError> {
+    let started = Instant::now();
+    let send = |token: Token| {
+        token_tx
+            .send(token)
+            .map_err(|_| InferenceError::custom("Failed to send token to channel."))
+    };
+
+    let seed = request.seed.unwrap_or_else(rand::random);
+    send(Token::Seed(seed))?;
+    // The server doesn't send the prompt back, so echo it like a local model does
+    if !request.continuation {
+        send(Token::Token(request.prompt.clone()))?;
+    }
+
+    let mut body = serde_json::json!({
+        "model": remote.model,
+        "prompt": request.prompt,
+        "max_tokens": request.max_tokens,
+        "seed": seed,
+        "stream": true,
+    });
+    if let Some(min_p) = request.min_p {
+        body["min_p"] = min_p.into();
+    }
+    if let Some(temperature) = request.temperature {
+        body["temperature"] = temperature.into();
+    }
+    if let Some(top_p) = request.top_p {
+        body["top_p"] = top_p.into();
+    }
+    if let Some(repeat_penalty) = request.repeat_penalty {
+        body["repetition_penalty"] = repeat_penalty.into();
+    }
+    // Not part of the OpenAI API, but llama.cpp's server and others accept it
+    if let Some(grammar) = &request.grammar {
+        body["grammar"] = grammar.as_str().into();
+    }
+
+    let url = format!("{}/v1/completions", remote.base_url.trim_end_matches('/'));
+    let mut http_request = reqwest::Client::new()
+        .post(&url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string());
+    if let Some(api_key) = &remote.api_key {
+        http_request = http_request.bearer_auth(api_key);
+    }
+    let server_error = |err: reqwest::Error| {
+        tracing::error!(url = %url, "Completion request failed: {err}");
+        InferenceError::custom(format!("The inference server failed to respond: {err}"))
+    };
+    // The server can stall at any point, so every wait on it can be interrupted
+    let stop = Stop {
+        request,
+        started,
+        cancelled,
+        shutdown,
+    };
+    let mut response = stop
+        .unless_stopped(http_request.send())
+        .await?
+        .and_then(|r| r.error_for_status())
+        .map_err(server_error)?;
+
+    let stops: Vec<_> = request
+        .stop_sequences
+        .iter()
+        .chain(&request.antiprompt)
+        .cloned()
+        .collect();
+    let mut stop_sequences = StopSequenceDetector::new(&stops);
+    let mut finished = false;
+    let mut count = 0;
+    let mut pending = Vec::new();
+
+    // The response is a stream of server-sent events, each a line of `data: {json}`
+    'stream: while let Some(bytes) = stop
+        .unless_stopped(response.chunk())
+        .await?
+        .map_err(server_error)?
+    {
+        pending.extend_from_slice(&bytes);
+        while let Some(newline) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:").map(str::trim) else { continue; };
+            if data == "[DONE]" {
+                break 'stream;
+            }
+
+            let chunk: CompletionChunk = serde_json::from_str(data).map_err(|err| {
+                InferenceError::custom(format!(
+                    "The inference server sent an invalid response: {err}"
+                ))
+            })?;
+            let Some(choice) = chunk.choices.into_iter().next() else { continue; };
+
+            // Servers usually send a token at a time
+            count += 1;
+            let (text, stopped) = stop_sequences.push(&choice.text);
+            if !text.is_empty() {
+                send(Token::Token(text))?;
+            }
+            if stopped {
+                finished = true;
+                break 'stream;
+            }
+            match choice.finish_reason.as_deref() {
+                Some("length") | None => {}
+                Some(_) => finished = true,
+            }
+        }
+    }
+
+    let duration = started.elapsed();
+    crate::metrics::METRICS.generation(count, duration);
+
+    let remaining = stop_sequences.flush();
+    if !remaining.is_empty() {
+        send(Token::Token(remaining))?;
+    }
+    if !finished {
+        send(Token::Truncated)?;
+    }
+    send(Token::Stats { count, duration })
+}
+
+/// The reasons a generation can stop before the server finishes.
+struct Stop<'a> {
+    request: &'a Request,
+    started: Instant,
+    cancelled: &'a AtomicBool,
+    shutdown: &'a AtomicBool,
+}
+impl Stop<'_> {
+    /// How often to check whether to stop while waiting on the server
+    const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+    fn check(&self) -> Result<(), InferenceError> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(InferenceError::Cancelled);
+        }
+        if self.shutdown.load(Ordering::SeqCst) {
+            return Err(InferenceError::ShuttingDown);
+        }
+        if matches!(self.request.timeout, Some(timeout) if self.started.elapsed() > timeout) {
+            return Err(InferenceError::TimedOut);
+        }
+        Ok(())
+    }
+
+    /// Waits for the future, giving up on it as soon as the generation is cancelled,
+    /// the bot shuts down or the generation times out.
+    async fn unless_stopped<T>(
+        &self,
+        future: impl Future<Output = T>,
+    ) -> Result<T, InferenceError> {
+        tokio::pin!(future);
+        loop {
+            self.check()?;
+            if let Ok(output) = tokio::time::timeout(Self::CHECK_INTERVAL, &mut future).await {
+                return Ok(output);
+            }
+        }
+    }
+}
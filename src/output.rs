@@ -0,0 +1,130 @@
+//! The destinations a generation's response can be streamed to, so that the
+//! generation pipeline doesn't depend on where the response ends up.
+
+use crate::{
+    generation::{InferenceError, Token},
+    metrics, stats,
+};
+use serenity::{
+    async_trait,
+    futures::{Stream, StreamExt},
+};
+
+/// Somewhere a response is written to as it's generated.
+#[async_trait]
+pub trait OutputSink: Send {
+    /// Called periodically while waiting for the first token, so that the sink
+    /// can show that something is happening.
+    async fn waiting(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+    /// Shows the request's position in the queue, before it starts generating.
+    async fn queue_position(&mut self, _position: usize) -> anyhow::Result<()> {
+        Ok(())
+    }
+    /// Shows how much of the prompt the model has read, out of the total number
+    /// of tokens, while it reads a long prompt.
+    async fn prompt_progress(&mut self, _fed: usize, _total: usize) -> anyhow::Result<()> {
+        Ok(())
+    }
+    /// Adds text from the conversation so far, played back before the response.
+    fn push_replayed(&mut self, _text: &str) {}
+    /// Adds the next piece of the response.
+    async fn push_token(&mut self, token: &str) -> anyhow::Result<()>;
+    /// Adds a short note about the generation, to be shown with the response.
+    fn add_note(&mut self, _note: String) {}
+    /// Ends the response because the user cancelled it.
+    async fn cancelled(&mut self) -> anyhow::Result<()>;
+    /// Ends the response because the generation failed.
+    async fn error(&mut self, message: &str) -> anyhow::Result<()>;
+    /// Completes the response. `truncated` is set if the generation ran out of
+    /// tokens before the model finished.
+    async fn finalize(&mut self, truncated: bool) -> anyhow::Result<()>;
+}
+
+/// Feeds a generation's tokens into the sink until the generation ends. Returns
+/// whether the response was truncated, or `None` if the generation failed, in
+/// which case the sink has already been told. The sink is not finalized, so that
+/// the caller can inspect it first. Completed generations are counted in `stats`.
+pub async fn stream_to_sink(
+    mut stream: impl Stream<Item = Token> + Unpin + Send,
+    sink: &mut dyn OutputSink,
+    show_stats: bool,
+    stats: &stats::Stats,
+) -> anyhow::Result<Option<bool>> {
+    // Nudge the sink until the response starts arriving, as processing the
+    // prompt can take a while
+    let mut waiting = Some(tokio::time::interval(std::time::Duration::from_secs(8)));
+
+    let mut truncated = false;
+    loop {
+        let token = match &mut waiting {
+            Some(interval) => tokio::select! {
+                token = stream.next() => token,
+                _ = interval.tick() => {
+                    sink.waiting().await?;
+                    continue;
+                }
+            },
+            None => stream.next().await,
+        };
+        let Some(token) = token else { break; };
+
+        match token {
+            Token::Token(t) => {
+                waiting = None;
+                sink.push_token(&t).await?;
+            }
+            Token::Replayed(t) => sink.push_replayed(&t),
+            Token::QueuePosition(position) => {
+                tracing::debug!(position, "Queued");
+                sink.queue_position(position).await?
+            }
+            Token::PromptProgress { fed, total } => sink.prompt_progress(fed, total).await?,
+            Token::Seed(seed) => sink.add_note(format!("seed: {seed}")),
+            Token::Context { used, size } => {
+                sink.add_note(format!("context: {used}/{size} tokens"));
+                // Conversations that run out of room start over, forgetting everything
+                if used * 10 >= size * 9 {
+                    sink.add_note(
+                        "the conversation is nearly out of room and will start over soon; use `/reset` to start over now"
+                            .to_string(),
+                    );
+                }
+            }
+            Token::Truncated => truncated = true,
+            Token::Stats { count, duration } => {
+                tracing::info!(tokens = count, duration = ?duration, "Generation stats");
+                stats.generation(count, duration);
+                if show_stats {
+                    let seconds = duration.as_secs_f64();
+                    let tokens_per_second = if seconds > 0.0 {
+                        count as f64 / seconds
+                    } else {
+                        0.0
+                    };
+                    sink.add_note(format!(
+                        "{count} tokens in {seconds:.1}s ({tokens_per_second:.1} tok/s)"
+                    ));
+                }
+            }
+            Token::Error(err) => {
+                tracing::info!("Generation stopped: {err}");
+                match &err {
+                    InferenceError::Cancelled => metrics::METRICS.cancellation(),
+                    _ => metrics::METRICS.error(),
+                }
+                match err {
+                    InferenceError::Cancelled => sink.cancelled().await?,
+                    InferenceError::Custom(m) => sink.error(&m).await?,
+                    err @ (InferenceError::ShuttingDown | InferenceError::TimedOut) => {
+                        sink.error(&err.to_string()).await?
+                    }
+                };
+                return Ok(None);
+            }
+        }
+    }
+
+    Ok(Some(truncated))
+}
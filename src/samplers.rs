@@ -0,0 +1,39 @@
+//! Samplers that `llm` doesn't provide itself.
+
+use llm::samplers::llm_samplers::types::{HasSamplerResources, Logits, Sampler};
+
+/// Min-p sampling: removes every token whose probability is less than `min_p`
+/// times the probability of the most likely token, so that the cutoff scales
+/// with how confident the model is.
+#[derive(Debug, Clone)]
+pub struct SampleMinP {
+    min_p: f32,
+}
+impl SampleMinP {
+    pub fn new(min_p: f32) -> Self {
+        Self { min_p }
+    }
+}
+impl Sampler<llm::TokenId, f32> for SampleMinP {
+    fn sample<'a>(
+        &mut self,
+        _res: &mut dyn HasSamplerResources<TokenId = llm::TokenId>,
+        logits: &'a mut Logits<llm::TokenId, f32>,
+    ) -> anyhow::Result<&'a mut Logits<llm::TokenId, f32>> {
+        if logits.is_empty() || self.min_p <= 0.0 {
+            return Ok(logits);
+        }
+
+        // This sorts the logits by descending probability
+        logits.softmax()?;
+        let threshold = logits[0].prob * self.min_p;
+        let keep = logits
+            .iter()
+            .position(|l| l.prob < threshold)
+            .unwrap_or(logits.len())
+            .max(1);
+        logits.truncate(keep);
+
+        Ok(logits)
+    }
+}
@@ -0,0 +1,277 @@
+//! Cron expressions for [crate::config::Schedule]s. Only the standard five
+//! fields are supported, evaluated in UTC, which is all that's needed to check
+//! each minute whether a schedule is due.
+
+/// A parsed cron expression: minute, hour, day of month, month and day of week.
+/// Each field is `*`, a number, a range (`1-5`), a step (`*/15` or `0-30/10`),
+/// or a comma-separated list of them.
+pub(crate) struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of month and day of week fields don't start with `*`, so
+    /// `*/2` isn't restricted. If both are restricted, either matching is
+    /// enough, as in standard cron.
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+impl Cron {
+    pub(crate) fn parse(expression: &str) -> anyhow::Result<Self> {
+        let fields: Vec<_> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            anyhow::bail!(
+                "expected five fields (minute, hour, day of month, month and day of week), found {}",
+                fields.len()
+            );
+        };
+
+        // Sunday can be either 0 or 7
+        let mut weekday_bits = parse_field(weekdays, "day of week", 0, 7)?;
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1;
+        }
+
+        Ok(Self {
+            minutes: parse_field(minutes, "minute", 0, 59)?,
+            hours: parse_field(hours, "hour", 0, 23)?,
+            days: parse_field(days, "day of month", 1, 31)?,
+            months: parse_field(months, "month", 1, 12)?,
+            weekdays: weekday_bits,
+            days_restricted: !days.starts_with('*'),
+            weekdays_restricted: !weekdays.starts_with('*'),
+        })
+    }
+
+    pub(crate) fn matches(&self, time: &UtcTime) -> bool {
+        let is_set = |bits: u64, value: u32| bits & (1 << value) != 0;
+        let day = is_set(self.days, time.day);
+        let weekday = is_set(self.weekdays, time.weekday);
+        let day_matches = if self.days_restricted && self.weekdays_restricted {
+            day || weekday
+        } else {
+            day && weekday
+        };
+
+        is_set(self.minutes, time.minute)
+            && is_set(self.hours, time.hour)
+            && is_set(self.months, time.month)
+            && day_matches
+    }
+}
+
+/// Parses one field of a cron expression into a bitmask of the values it allows.
+fn parse_field(field: &str, name: &str, min: u32, max: u32) -> anyhow::Result<u64> {
+    let parse_value = |value: &str| -> anyhow::Result<u32> {
+        let value: u32 = value
+            .parse()
+            .map_err(|_| anyhow::anyhow!("the {name} `{value}` is not a number"))?;
+        anyhow::ensure!(
+            (min..=max).contains(&value),
+            "the {name} {value} is not between {min} and {max}"
+        );
+        Ok(value)
+    };
+
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .ok()
+                    .filter(|&s| s > 0)
+                    .ok_or_else(|| anyhow::anyhow!("the {name} step `{step}` is invalid"))?;
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (parse_value(start)?, parse_value(end)?),
+                // A single value with a step runs from it to the end, as in `5/15`
+                None if step > 1 => (parse_value(range)?, max),
+                None => {
+                    let value = parse_value(range)?;
+                    (value, value)
+                }
+            },
+        };
+        anyhow::ensure!(start <= end, "the {name} range `{range}` is backwards");
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// The parts of a UTC time that cron expressions match against.
+pub(crate) struct UtcTime {
+    pub minute: u32,
+    pub hour: u32,
+    /// 1 to 31
+    pub day: u32,
+    /// 1 to 12
+    pub month: u32,
+    /// 0 (Sunday) to 6
+    pub weekday: u32,
+}
+impl UtcTime {
+    pub(crate) fn from_unix_seconds(seconds: u64) -> Self {
+        let days = seconds / 86_400;
+        let seconds_of_day = seconds % 86_400;
+
+        // The Unix epoch was a Thursday. The date is worked out as in Howard
+        // Hinnant's `civil_from_days`, with years starting in March.
+        let shifted_days = days + 719_468;
+        let day_of_era = shifted_days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+
+        Self {
+            minute: (seconds_of_day % 3600 / 60) as u32,
+            hour: (seconds_of_day / 3600) as u32,
+            day: (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32,
+            month: if shifted_month < 10 {
+                shifted_month + 3
+            } else {
+                shifted_month - 9
+            } as u32,
+            weekday: ((days + 4) % 7) as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(minute: u32, hour: u32, day: u32, month: u32, weekday: u32) -> UtcTime {
+        UtcTime {
+            minute,
+            hour,
+            day,
+            month,
+            weekday,
+        }
+    }
+
+    fn matching_minutes(expression: &str) -> Vec<u32> {
+        let cron = Cron::parse(expression).unwrap();
+        (0..60)
+            .filter(|&minute| cron.matches(&time(minute, 0, 1, 1, 0)))
+            .collect()
+    }
+
+    #[test]
+    fn steps_run_over_the_whole_field() {
+        assert_eq!(matching_minutes("*/15 * * * *"), vec![0, 15, 30, 45]);
+    }
+
+    #[test]
+    fn steps_run_over_ranges() {
+        assert_eq!(matching_minutes("0-30/10 * * * *"), vec![0, 10, 20, 30]);
+    }
+
+    #[test]
+    fn steps_from_a_single_value_run_to_the_end() {
+        assert_eq!(matching_minutes("5/15 * * * *"), vec![5, 20, 35, 50]);
+    }
+
+    #[test]
+    fn lists_combine_values_and_ranges() {
+        assert_eq!(matching_minutes("1,5-7,59 * * * *"), vec![1, 5, 6, 7, 59]);
+    }
+
+    #[test]
+    fn sunday_can_be_seven() {
+        let cron = Cron::parse("0 0 * * 7").unwrap();
+        assert!(cron.matches(&time(0, 0, 7, 1, 0)));
+        assert!(!cron.matches(&time(0, 0, 8, 1, 1)));
+    }
+
+    #[test]
+    fn either_day_matches_if_both_are_restricted() {
+        // The 1st of the month, or any Monday
+        let cron = Cron::parse("0 0 1 * 1").unwrap();
+        assert!(cron.matches(&time(0, 0, 1, 1, 3)));
+        assert!(cron.matches(&time(0, 0, 6, 1, 1)));
+        assert!(!cron.matches(&time(0, 0, 7, 1, 2)));
+    }
+
+    #[test]
+    fn both_days_must_match_if_only_one_is_restricted() {
+        let cron = Cron::parse("0 0 1 * *").unwrap();
+        assert!(cron.matches(&time(0, 0, 1, 1, 3)));
+        assert!(!cron.matches(&time(0, 0, 2, 1, 4)));
+
+        let cron = Cron::parse("0 0 * * 1").unwrap();
+        assert!(cron.matches(&time(0, 0, 6, 1, 1)));
+        assert!(!cron.matches(&time(0, 0, 7, 1, 2)));
+    }
+
+    #[test]
+    fn stepped_stars_are_not_restricted() {
+        // Odd days that are also Mondays, rather than odd days or Mondays
+        let cron = Cron::parse("0 0 */2 * 1").unwrap();
+        assert!(cron.matches(&time(0, 0, 3, 1, 1)));
+        assert!(!cron.matches(&time(0, 0, 3, 1, 2)));
+        assert!(!cron.matches(&time(0, 0, 2, 1, 1)));
+    }
+
+    #[test]
+    fn invalid_expressions_are_rejected() {
+        assert!(Cron::parse("* * * *").is_err());
+        assert!(Cron::parse("60 * * * *").is_err());
+        assert!(Cron::parse("* * 0 * *").is_err());
+        assert!(Cron::parse("30-10 * * * *").is_err());
+        assert!(Cron::parse("*/0 * * * *").is_err());
+        assert!(Cron::parse("x * * * *").is_err());
+    }
+
+    fn assert_time(seconds: u64, expected: UtcTime) {
+        let actual = UtcTime::from_unix_seconds(seconds);
+        assert_eq!(
+            (
+                actual.minute,
+                actual.hour,
+                actual.day,
+                actual.month,
+                actual.weekday
+            ),
+            (
+                expected.minute,
+                expected.hour,
+                expected.day,
+                expected.month,
+                expected.weekday
+            ),
+        );
+    }
+
+    #[test]
+    fn the_epoch_was_a_thursday() {
+        assert_time(0, time(0, 0, 1, 1, 4));
+    }
+
+    #[test]
+    fn leap_days_are_counted() {
+        // 2024-02-29 12:34, a Thursday
+        assert_time(1_709_210_040, time(34, 12, 29, 2, 4));
+        // 2024-03-01 00:00, a Friday
+        assert_time(1_709_251_200, time(0, 0, 1, 3, 5));
+    }
+
+    #[test]
+    fn years_roll_over() {
+        // 2023-12-31 23:59, a Sunday
+        assert_time(1_704_067_140, time(59, 23, 31, 12, 0));
+        // 2024-01-01 00:00, a Monday
+        assert_time(1_704_067_200, time(0, 0, 1, 1, 1));
+    }
+}
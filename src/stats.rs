@@ -0,0 +1,111 @@
+//! Lifetime totals of the generations the bot has served, shown by `/stats`.
+//! Unlike [crate::metrics], these are saved to a file, so that they add up
+//! across restarts.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+pub struct Stats {
+    started: Instant,
+    generations: AtomicU64,
+    tokens: AtomicU64,
+    /// The total time spent generating, in milliseconds
+    generation_millis: AtomicU64,
+    path: PathBuf,
+    /// Held while saving, so that concurrent saves don't interleave
+    save_lock: Mutex<()>,
+}
+
+/// The totals as they're saved to the file.
+#[derive(Serialize, Deserialize, Default)]
+struct SavedStats {
+    generations: u64,
+    tokens: u64,
+    generation_millis: u64,
+}
+
+/// A copy of the totals at one point in time.
+pub struct Snapshot {
+    pub uptime: Duration,
+    pub generations: u64,
+    pub tokens: u64,
+    pub generation_time: Duration,
+}
+impl Snapshot {
+    /// The average generation speed over the bot's lifetime.
+    pub fn tokens_per_second(&self) -> f64 {
+        let seconds = self.generation_time.as_secs_f64();
+        if seconds > 0.0 {
+            self.tokens as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Stats {
+    /// Starts counting from the totals saved at `path`. If there aren't any, or
+    /// they can't be read, counting starts from zero.
+    pub fn load(path: &Path) -> Self {
+        let saved = match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|err| {
+                tracing::warn!(path = %path.display(), "Failed to read saved stats: {err}");
+                SavedStats::default()
+            }),
+            Err(_) => SavedStats::default(),
+        };
+
+        Self {
+            started: Instant::now(),
+            generations: AtomicU64::new(saved.generations),
+            tokens: AtomicU64::new(saved.tokens),
+            generation_millis: AtomicU64::new(saved.generation_millis),
+            path: path.to_owned(),
+            save_lock: Mutex::new(()),
+        }
+    }
+
+    /// Records a generation that ran to completion.
+    pub fn generation(&self, tokens: usize, duration: Duration) {
+        self.generations.fetch_add(1, Ordering::Relaxed);
+        self.tokens.fetch_add(tokens as u64, Ordering::Relaxed);
+        self.generation_millis
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            uptime: self.started.elapsed(),
+            generations: self.generations.load(Ordering::Relaxed),
+            tokens: self.tokens.load(Ordering::Relaxed),
+            generation_time: Duration::from_millis(self.generation_millis.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Saves the totals to the file. They're written to a temporary file first,
+    /// so that the totals aren't lost if the bot stops partway through.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let _guard = self.save_lock.lock().unwrap();
+        let saved = SavedStats {
+            generations: self.generations.load(Ordering::Relaxed),
+            tokens: self.tokens.load(Ordering::Relaxed),
+            generation_millis: self.generation_millis.load(Ordering::Relaxed),
+        };
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let partial_path = self.path.with_extension("tmp");
+        std::fs::write(&partial_path, serde_json::to_string(&saved)?)?;
+        std::fs::rename(&partial_path, &self.path)?;
+        Ok(())
+    }
+}
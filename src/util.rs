@@ -1,6 +1,6 @@
 use serenity::{
     async_trait,
-    http::Http,
+    http::{Http, HttpError},
     model::{
         prelude::{
             interaction::{
@@ -18,6 +18,79 @@ use serenity::{
 };
 use std::future::Future;
 
+/// Whether or not the error is Discord rejecting a request for exceeding a rate limit.
+pub fn is_rate_limited(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<serenity::Error>() {
+        Some(serenity::Error::Http(err)) => matches!(
+            err.as_ref(),
+            HttpError::UnsuccessfulRequest(response) if response.status_code.as_u16() == 429
+        ),
+        _ => false,
+    }
+}
+
+/// Explains how to give the bot the access it needs, if the error is Discord
+/// rejecting a request because the bot doesn't have it.
+pub fn missing_access_guidance(err: &anyhow::Error) -> Option<&'static str> {
+    let Some(serenity::Error::Http(err)) = err.downcast_ref::<serenity::Error>() else { return None; };
+    let HttpError::UnsuccessfulRequest(response) = err.as_ref() else { return None; };
+    match response.error.code {
+        // Missing Access
+        50001 => Some(
+            "The bot needs the `applications.commands` scope to register its commands. \
+             Invite it again with the `bot` and `applications.commands` scopes \
+             (OAuth2 > URL Generator in the Discord developer portal), and check that \
+             it's still in the guilds it has commands for.",
+        ),
+        // Missing Permissions
+        50013 => Some(
+            "The bot is missing permissions. Invite it again with the `Send Messages` and \
+             `Use Slash Commands` permissions, or grant them to its role.",
+        ),
+        _ if response.status_code.as_u16() == 403 => Some(
+            "Discord refused the request. Check that the bot was invited with the `bot` \
+             and `applications.commands` scopes.",
+        ),
+        _ => None,
+    }
+}
+
+/// Whether or not the error is likely to go away if the request is made again,
+/// such as a Discord server error or a dropped connection.
+fn is_transient(err: &serenity::Error) -> bool {
+    match err {
+        serenity::Error::Http(err) => match err.as_ref() {
+            HttpError::UnsuccessfulRequest(response) => response.status_code.is_server_error(),
+            HttpError::Request(_) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Makes the request, retrying it with a short backoff if it fails with a
+/// transient error.
+pub async fn retry_transient<T, Fut>(mut request: impl FnMut() -> Fut) -> serenity::Result<T>
+where
+    Fut: Future<Output = serenity::Result<T>>,
+{
+    const ATTEMPTS: usize = 3;
+
+    let mut delay = std::time::Duration::from_millis(250);
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Err(err) if attempt < ATTEMPTS && is_transient(&err) => {
+                tracing::warn!(attempt, "Retrying failed Discord request: {err}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
 pub fn get_value<'a>(
     options: &'a [CommandDataOption],
     name: &'a str,
@@ -35,6 +108,13 @@ pub fn value_to_string(v: &CommandDataOptionValue) -> Option<String> {
     }
 }
 
+pub fn value_to_bool(v: &CommandDataOptionValue) -> Option<bool> {
+    match v {
+        CommandDataOptionValue::Boolean(v) => Some(*v),
+        _ => None,
+    }
+}
+
 pub fn value_to_integer(v: &CommandDataOptionValue) -> Option<i64> {
     match v {
         CommandDataOptionValue::Integer(v) => Some(*v),
@@ -42,6 +122,13 @@ pub fn value_to_integer(v: &CommandDataOptionValue) -> Option<i64> {
     }
 }
 
+pub fn value_to_number(v: &CommandDataOptionValue) -> Option<f64> {
+    match v {
+        CommandDataOptionValue::Number(v) => Some(*v),
+        _ => None,
+    }
+}
+
 #[async_trait]
 pub trait DiscordInteraction: Send + Sync {
     async fn create(&self, http: &Http, message: &str) -> anyhow::Result<()>;
@@ -70,21 +157,19 @@ macro_rules! implement_interaction {
             async fn get_interaction_message(&self, http: &Http) -> anyhow::Result<Message> {
                 Ok(self.get_interaction_response(http).await?)
             }
+            // Edited through the interaction rather than the channel, as ephemeral
+            // responses can't be edited through the channel
             async fn edit(&self, http: &Http, message: &str) -> anyhow::Result<()> {
-                Ok(self
-                    .get_interaction_message(http)
-                    .await?
-                    .edit(http, |m| m.content(message))
-                    .await?)
+                self.edit_original_interaction_response(http, |m| m.content(message))
+                    .await?;
+                Ok(())
             }
             async fn create_or_edit(&self, http: &Http, message: &str) -> anyhow::Result<()> {
-                Ok(
-                    if let Ok(mut msg) = self.get_interaction_message(http).await {
-                        msg.edit(http, |m| m.content(message)).await?
-                    } else {
-                        self.create(http, message).await?
-                    },
-                )
+                if self.get_interaction_message(http).await.is_ok() {
+                    self.edit(http, message).await
+                } else {
+                    self.create(http, message).await
+                }
             }
 
             fn channel_id(&self) -> ChannelId {
@@ -121,6 +206,84 @@ implement_interaction!(ApplicationCommandInteraction);
 implement_interaction!(MessageComponentInteraction);
 implement_interaction!(ModalSubmitInteraction);
 
+/// Formats a duration coarsely for people to read, such as `3d 4h 12m`.
+pub fn format_duration(duration: std::time::Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    match (days, hours) {
+        (0, 0) => format!("{minutes}m"),
+        (0, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h {minutes}m"),
+    }
+}
+
+/// Responds to the command with a message that only the invoking user can see.
+pub async fn create_ephemeral_response(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    message: &str,
+) -> anyhow::Result<()> {
+    Ok(cmd
+        .create_interaction_response(http, |response| {
+            response
+                .kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|m| m.content(message).ephemeral(true))
+        })
+        .await?)
+}
+
+/// Acknowledges the command straight away, as Discord gives up on it after three
+/// seconds, so that the response can be filled in afterwards.
+pub async fn defer_response(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    ephemeral: bool,
+) -> anyhow::Result<()> {
+    Ok(retry_transient(move || {
+        cmd.create_interaction_response(http, move |response| {
+            response
+                .kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                .interaction_response_data(|message| message.ephemeral(ephemeral))
+        })
+    })
+    .await?)
+}
+
+/// Responds to a command deferred with [defer_response] with a message that only
+/// the invoking user can see. A public deferral can't be made ephemeral, so it's
+/// deleted and replaced with an ephemeral followup.
+pub async fn create_deferred_ephemeral_response(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    deferred_ephemeral: bool,
+    message: &str,
+) -> anyhow::Result<()> {
+    if deferred_ephemeral {
+        cmd.edit_original_interaction_response(http, |m| m.content(message))
+            .await?;
+    } else {
+        cmd.delete_original_interaction_response(http).await?;
+        cmd.create_followup_message(http, |m| m.content(message).ephemeral(true))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Responds to the component interaction with a message that only the invoking user can see.
+pub async fn create_ephemeral_component_response(
+    cmp: &MessageComponentInteraction,
+    http: &Http,
+    message: &str,
+) -> anyhow::Result<()> {
+    Ok(cmp
+        .create_interaction_response(http, |response| {
+            response
+                .kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|m| m.content(message).ephemeral(true))
+        })
+        .await?)
+}
+
 /// Runs the [body] and edits the interaction response if an error occurs.
 pub async fn run_and_report_error(
     interaction: &dyn DiscordInteraction,
@@ -128,9 +291,12 @@ pub async fn run_and_report_error(
     body: impl Future<Output = anyhow::Result<()>>,
 ) {
     if let Err(err) = body.await {
-        interaction
+        tracing::error!("{err:?}");
+        if let Err(report_err) = interaction
             .create_or_edit(http, &format!("Error: {err}"))
             .await
-            .unwrap();
+        {
+            tracing::error!("Failed to report the error: {report_err:?}");
+        }
     }
 }
@@ -0,0 +1,362 @@
+//! Tests for the generation pipeline that don't need model weights: each test
+//! points a model with the `openai` backend at a local server that replies with
+//! a scripted stream of completion chunks.
+
+use std::{collections::HashMap, time::Duration};
+
+use llmcord::{Generator, InferenceError, RemoteModel, Request, Token};
+use serenity::{futures::StreamExt, model::prelude::MessageId};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+const MODEL: &str = "test-model";
+
+/// A scripted response from the server.
+struct Script {
+    status: u16,
+    /// The server-sent events to stream, in order
+    events: Vec<String>,
+    /// How long to wait before each event
+    delay: Duration,
+}
+impl Script {
+    fn stream(events: Vec<String>) -> Self {
+        Self {
+            status: 200,
+            events,
+            delay: Duration::ZERO,
+        }
+    }
+}
+
+/// A completion chunk with the text, as an event.
+fn chunk(text: &str, finish_reason: Option<&str>) -> String {
+    let chunk = serde_json::json!({
+        "choices": [{ "text": text, "finish_reason": finish_reason }],
+    });
+    format!("data: {chunk}\n\n")
+}
+
+fn done() -> String {
+    "data: [DONE]\n\n".to_string()
+}
+
+/// Serves a single request with the script. Returns the server's URL, and a handle
+/// that resolves to the body of the request it received.
+async fn serve(script: Script) -> (String, JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    let handle = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let body = read_request(&mut stream).await;
+
+        let reason = if script.status == 200 { "OK" } else { "Error" };
+        let head = format!(
+            "HTTP/1.1 {} {reason}\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n",
+            script.status
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        for event in &script.events {
+            tokio::time::sleep(script.delay).await;
+            // The client hangs up when it stops early, which is expected
+            if stream.write_all(event.as_bytes()).await.is_err() {
+                break;
+            }
+            stream.flush().await.ok();
+        }
+        stream.shutdown().await.ok();
+
+        body
+    });
+
+    (url, handle)
+}
+
+/// Reads an HTTP request from the stream, returning its body.
+async fn read_request(stream: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let read = stream.read(&mut buffer).await.unwrap();
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+
+        let text = String::from_utf8_lossy(&request);
+        let Some(head_end) = text.find("\r\n\r\n") else { continue; };
+        let content_length = text[..head_end]
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("content-length")
+                    .then(|| value.trim().parse::<usize>().ok())?
+            })
+            .unwrap_or(0);
+        let body_start = head_end + 4;
+        if request.len() >= body_start + content_length {
+            return String::from_utf8_lossy(&request[body_start..]).into_owned();
+        }
+    }
+    String::new()
+}
+
+fn generator(url: String) -> Generator {
+    Generator::new(HashMap::new(), Duration::from_secs(60), None).with_remote_models(HashMap::from(
+        [(
+            MODEL.to_string(),
+            RemoteModel {
+                base_url: url,
+                api_key: None,
+                model: MODEL.to_string(),
+            },
+        )],
+    ))
+}
+
+fn request(prompt: &str) -> Request {
+    Request {
+        model: MODEL.to_string(),
+        prompt: prompt.to_string(),
+        batch_size: 8,
+        thread_count: 1,
+        response_token_reserve: 0,
+        stop_sequences: vec![],
+        antiprompt: None,
+        max_tokens: 16,
+        token_biases: vec![],
+        discourage_eos: None,
+        min_p: None,
+        temperature: None,
+        top_p: None,
+        repeat_penalty: None,
+        grammar: None,
+        context_size: None,
+        timeout: None,
+        message_id: MessageId(1),
+        seed: Some(42),
+        conversation: None,
+        play_back_previous_tokens: false,
+        continuation: false,
+    }
+}
+
+/// The text of the tokens, including the echoed prompt.
+fn text(tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .filter_map(|t| match t {
+            Token::Token(text) => Some(text.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn is_truncated(tokens: &[Token]) -> bool {
+    tokens.iter().any(|t| matches!(t, Token::Truncated))
+}
+
+#[tokio::test]
+async fn streams_the_completion() {
+    let (url, server) = serve(Script::stream(vec![
+        chunk("Hello", None),
+        chunk(", world", Some("stop")),
+        done(),
+    ]))
+    .await;
+    let generator = generator(url);
+
+    let tokens: Vec<_> = generator
+        .generate(request("Say hi: "))
+        .unwrap()
+        .collect()
+        .await;
+
+    assert!(matches!(tokens.first(), Some(Token::Seed(42))));
+    assert_eq!(text(&tokens), "Say hi: Hello, world");
+    assert!(!is_truncated(&tokens));
+    assert!(matches!(tokens.last(), Some(Token::Stats { count: 2, .. })));
+
+    let body: serde_json::Value = serde_json::from_str(&server.await.unwrap()).unwrap();
+    assert_eq!(body["model"], MODEL);
+    assert_eq!(body["prompt"], "Say hi: ");
+    assert_eq!(body["seed"], 42);
+    assert_eq!(body["stream"], true);
+}
+
+#[tokio::test]
+async fn stops_at_stop_sequences() {
+    let (url, _server) = serve(Script::stream(vec![
+        chunk("Hello", None),
+        chunk(" END", None),
+        chunk(" more", None),
+        done(),
+    ]))
+    .await;
+    let generator = generator(url);
+
+    let tokens: Vec<_> = generator
+        .generate(Request {
+            stop_sequences: vec!["END".to_string()],
+            ..request("")
+        })
+        .unwrap()
+        .collect()
+        .await;
+
+    assert_eq!(text(&tokens), "Hello ");
+    assert!(!is_truncated(&tokens));
+}
+
+#[tokio::test]
+async fn marks_responses_that_run_out_of_tokens_as_truncated() {
+    let (url, _server) = serve(Script::stream(vec![
+        chunk("Once upon", None),
+        chunk(" a time", Some("length")),
+        done(),
+    ]))
+    .await;
+    let generator = generator(url);
+
+    let tokens: Vec<_> = generator.generate(request("")).unwrap().collect().await;
+
+    assert_eq!(text(&tokens), "Once upon a time");
+    assert!(is_truncated(&tokens));
+}
+
+#[tokio::test]
+async fn cancels_the_generation() {
+    let (url, _server) = serve(Script {
+        status: 200,
+        events: (0..100).map(|_| chunk(" word", None)).collect(),
+        delay: Duration::from_millis(20),
+    })
+    .await;
+    let generator = generator(url);
+
+    let mut stream = generator.generate(request("")).unwrap();
+    let mut tokens = vec![];
+    while let Some(token) = stream.next().await {
+        if matches!(token, Token::Token(_)) {
+            generator.cancel(MessageId(1));
+        }
+        tokens.push(token);
+    }
+
+    assert!(matches!(
+        tokens.last(),
+        Some(Token::Error(InferenceError::Cancelled))
+    ));
+    assert!(text(&tokens).len() < " word".len() * 100);
+}
+
+#[tokio::test]
+async fn cancels_while_the_server_stalls() {
+    let (url, _server) = serve(Script {
+        status: 200,
+        events: vec![chunk(" word", None)],
+        delay: Duration::from_secs(60),
+    })
+    .await;
+    let generator = generator(url);
+
+    let stream = generator.generate(request("")).unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    generator.cancel(MessageId(1));
+    let tokens: Vec<_> = tokio::time::timeout(Duration::from_secs(5), stream.collect())
+        .await
+        .expect("the cancellation should not wait for the server");
+
+    assert!(matches!(
+        tokens.last(),
+        Some(Token::Error(InferenceError::Cancelled))
+    ));
+}
+
+#[tokio::test]
+async fn times_out_while_the_server_stalls() {
+    let (url, _server) = serve(Script {
+        status: 200,
+        events: vec![chunk(" word", None)],
+        delay: Duration::from_secs(60),
+    })
+    .await;
+    let generator = generator(url);
+
+    let stream = generator
+        .generate(Request {
+            timeout: Some(Duration::from_millis(200)),
+            ..request("")
+        })
+        .unwrap();
+    let tokens: Vec<_> = tokio::time::timeout(Duration::from_secs(5), stream.collect())
+        .await
+        .expect("the timeout should not wait for the server");
+
+    assert!(matches!(
+        tokens.last(),
+        Some(Token::Error(InferenceError::TimedOut))
+    ));
+}
+
+#[tokio::test]
+async fn reports_server_errors() {
+    let (url, _server) = serve(Script {
+        status: 500,
+        events: vec![],
+        delay: Duration::ZERO,
+    })
+    .await;
+    let generator = generator(url);
+
+    let tokens: Vec<_> = generator.generate(request("")).unwrap().collect().await;
+
+    match tokens.last() {
+        Some(Token::Error(InferenceError::Custom(message))) => {
+            assert!(message.contains("failed to respond"), "{message}")
+        }
+        _ => panic!("expected the generation to fail"),
+    }
+}
+
+#[tokio::test]
+async fn reports_invalid_responses() {
+    let (url, _server) = serve(Script::stream(vec!["data: {not json\n\n".to_string()])).await;
+    let generator = generator(url);
+
+    let tokens: Vec<_> = generator.generate(request("")).unwrap().collect().await;
+
+    assert!(matches!(
+        tokens.last(),
+        Some(Token::Error(InferenceError::Custom(_)))
+    ));
+}
+
+#[tokio::test]
+async fn rejects_unknown_models() {
+    let generator = generator("http://127.0.0.1:9".to_string());
+
+    let result = generator.generate(Request {
+        model: "missing".to_string(),
+        ..request("")
+    });
+
+    assert!(matches!(result, Err(InferenceError::Custom(_))));
+}
+
+#[tokio::test]
+async fn rejects_requests_after_shutdown() {
+    let generator = generator("http://127.0.0.1:9".to_string());
+
+    generator.shutdown();
+
+    assert!(matches!(
+        generator.generate(request("")),
+        Err(InferenceError::ShuttingDown)
+    ));
+    assert!(generator.is_stopped());
+}